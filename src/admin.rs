@@ -0,0 +1,463 @@
+use axum::extract::{ Path, Query, Request, State };
+use axum::http::{ header, HeaderMap, StatusCode };
+use axum::middleware::{ self, Next };
+use axum::response::{ IntoResponse, Response };
+use axum::routing::{ get, post };
+use axum::{ Json, Router };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::sync::Arc;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::delivery::{ Delivery, DeliveryKey };
+use crate::reload::Playlists;
+use crate::secrets::redact;
+use crate::store::{ PauseScope, PollStatus, Video, VideoStore };
+use crate::{ Playlist, WebhookType };
+
+/// The admin API of daemon mode
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Keep this on a private interface; the token is the only thing guarding it
+    #[serde(default = "AdminConfig::default_listen")]
+    pub listen: String,
+    /// Every request must carry `Authorization: Bearer <token>`
+    pub token: String,
+}
+
+impl std::fmt::Debug for AdminConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AdminConfig")
+            .field("listen", &self.listen)
+            .field("token", &"***")
+            .finish()
+    }
+}
+
+impl AdminConfig {
+    fn default_listen() -> String {
+        "127.0.0.1:8081".to_string()
+    }
+}
+
+struct Admin {
+    config: AdminConfig,
+    playlists: Playlists,
+    store: Arc<dyn VideoStore>,
+    /// Wakes the daemon up to poll a playlist right away
+    wake: UnboundedSender<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct PlaylistState {
+    id: String,
+    name: String,
+    paused: bool,
+    last_poll: Option<PollStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct VideoState {
+    #[serde(flatten)]
+    video: Video,
+    deliveries: Vec<Delivery>,
+}
+
+#[derive(Debug, Deserialize)]
+struct VideoFilter {
+    playlist: Option<String>,
+}
+
+/// Picks deliveries to requeue or skip. Without a `target`, every target of the destination is picked.
+/// Webhook URLs can be given redacted, as `/videos` lists them
+#[derive(Debug, Deserialize)]
+struct DeliveryRef {
+    video: String,
+    playlist: String,
+    destination: WebhookType,
+    target: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Changed {
+    changed: usize,
+}
+
+/// Serves the admin API: playlists and their last poll, videos and their deliveries, requeuing or
+/// skipping deliveries, pausing playlists and destinations, and polling a playlist right away
+pub async fn serve(
+    config: AdminConfig,
+    playlists: Playlists,
+    store: Arc<dyn VideoStore>,
+    wake: UnboundedSender<String>
+) -> anyhow::Result<()> {
+    let admin = Arc::new(Admin {
+        config,
+        playlists,
+        store,
+        wake,
+    });
+
+    let app = router(admin.clone());
+    let listener = tokio::net::TcpListener::bind(&admin.config.listen).await?;
+    tracing::info!("Serving the admin API on {}", &admin.config.listen);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("The admin API stopped: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+fn router(admin: Arc<Admin>) -> Router {
+    Router::new()
+        .route("/playlists", get(list_playlists))
+        .route("/playlists/:playlist/pause", post(pause_playlist))
+        .route("/playlists/:playlist/resume", post(resume_playlist))
+        .route("/playlists/:playlist/poll", post(poll_playlist))
+        .route("/destinations/:destination/pause", post(pause_destination))
+        .route("/destinations/:destination/resume", post(resume_destination))
+        .route("/videos", get(list_videos))
+        .route("/deliveries/requeue", post(requeue))
+        .route("/deliveries/skip", post(skip))
+        .layer(middleware::from_fn_with_state(admin.clone(), authorize))
+        .with_state(admin)
+}
+
+async fn authorize(
+    State(admin): State<Arc<Admin>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next
+) -> Response {
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    let authorized = token.is_some_and(|token| is_same(token.as_bytes(), admin.config.token.as_bytes()));
+    if !authorized {
+        return failure(StatusCode::UNAUTHORIZED, "missing or wrong bearer token");
+    }
+
+    next.run(request).await
+}
+
+/// Compares the whole token whatever the first difference, so timing doesn't give it away
+fn is_same(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn failure(status: StatusCode, message: &str) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+fn internal(e: anyhow::Error) -> Response {
+    tracing::error!("The admin API failed: {:?}", e);
+    failure(StatusCode::INTERNAL_SERVER_ERROR, "the store failed, see the logs")
+}
+
+impl Admin {
+    /// The playlist as the latest configuration has it
+    fn playlist(&self, id: &str) -> Option<Playlist> {
+        self.playlists
+            .borrow()
+            .iter()
+            .find(|p| p.id == id)
+            .cloned()
+    }
+}
+
+async fn list_playlists(State(admin): State<Arc<Admin>>) -> Response {
+    let (pauses, polls) = match (admin.store.pauses().await, admin.store.polls().await) {
+        (Ok(pauses), Ok(polls)) => (pauses, polls),
+        (Err(e), _) | (_, Err(e)) => {
+            return internal(e);
+        }
+    };
+
+    let playlists = admin.playlists.borrow().clone();
+    let playlists: Vec<PlaylistState> = playlists
+        .iter()
+        .map(|p| PlaylistState {
+            id: p.id.clone(),
+            name: p.name.clone(),
+            paused: pauses.iter().any(|pause| pause.is(PauseScope::Playlist, &p.id)),
+            last_poll: polls
+                .iter()
+                .find(|poll| poll.playlist == p.id)
+                .cloned(),
+        })
+        .collect();
+
+    Json(playlists).into_response()
+}
+
+async fn pause_playlist(State(admin): State<Arc<Admin>>, Path(playlist): Path<String>) -> Response {
+    set_paused(&admin, PauseScope::Playlist, &playlist, true).await
+}
+
+async fn resume_playlist(State(admin): State<Arc<Admin>>, Path(playlist): Path<String>) -> Response {
+    set_paused(&admin, PauseScope::Playlist, &playlist, false).await
+}
+
+async fn pause_destination(State(admin): State<Arc<Admin>>, Path(destination): Path<String>) -> Response {
+    set_paused(&admin, PauseScope::Destination, &destination, true).await
+}
+
+async fn resume_destination(State(admin): State<Arc<Admin>>, Path(destination): Path<String>) -> Response {
+    set_paused(&admin, PauseScope::Destination, &destination, false).await
+}
+
+async fn set_paused(admin: &Admin, scope: PauseScope, name: &str, paused: bool) -> Response {
+    let known = match scope {
+        PauseScope::Playlist => admin.playlist(name).is_some(),
+        PauseScope::Destination =>
+            admin.playlists
+                .borrow()
+                .iter()
+                .flat_map(|p| &p.webhooks)
+                .any(|w| w.name.as_deref() == Some(name)),
+    };
+    if !known {
+        return failure(StatusCode::NOT_FOUND, &format!("no {} is called {}", scope.as_str(), name));
+    }
+
+    if let Err(e) = admin.store.set_paused(scope, name, paused).await {
+        return internal(e);
+    }
+    tracing::info!("{} the {} {}", if paused { "Paused" } else { "Resumed" }, scope.as_str(), name);
+
+    // A resumed playlist catches up right away instead of at its next scheduled poll
+    if !paused && scope == PauseScope::Playlist {
+        let _ = admin.wake.send(name.to_string());
+    }
+
+    StatusCode::NO_CONTENT.into_response()
+}
+
+async fn poll_playlist(State(admin): State<Arc<Admin>>, Path(playlist): Path<String>) -> Response {
+    if admin.playlist(&playlist).is_none() {
+        return failure(StatusCode::NOT_FOUND, &format!("no playlist is called {}", playlist));
+    }
+
+    match admin.wake.send(playlist) {
+        Ok(()) => StatusCode::ACCEPTED.into_response(),
+        Err(_) => failure(StatusCode::SERVICE_UNAVAILABLE, "the daemon is shutting down"),
+    }
+}
+
+async fn list_videos(State(admin): State<Arc<Admin>>, Query(filter): Query<VideoFilter>) -> Response {
+    let videos = match admin.store.videos(filter.playlist.as_deref()).await {
+        Ok(videos) => videos,
+        Err(e) => {
+            return internal(e);
+        }
+    };
+
+    let mut states = Vec::with_capacity(videos.len());
+    for video in videos {
+        let deliveries = match admin.store.deliveries(&video.id).await {
+            Ok(deliveries) => deliveries,
+            Err(e) => {
+                return internal(e);
+            }
+        };
+        let deliveries = deliveries
+            .into_iter()
+            .filter(|d| d.playlist == video.playlist)
+            .map(|d| Delivery { target: redact(&d.target), ..d })
+            .collect();
+        states.push(VideoState { video, deliveries });
+    }
+
+    Json(states).into_response()
+}
+
+async fn requeue(State(admin): State<Arc<Admin>>, Json(delivery): Json<DeliveryRef>) -> Response {
+    change(&admin, &delivery, true).await
+}
+
+async fn skip(State(admin): State<Arc<Admin>>, Json(delivery): Json<DeliveryRef>) -> Response {
+    change(&admin, &delivery, false).await
+}
+
+/// Requeues or skips every delivery `delivery` picks among the playlist's configured sinks
+async fn change(admin: &Admin, delivery: &DeliveryRef, requeue: bool) -> Response {
+    let playlist = match admin.playlist(&delivery.playlist) {
+        Some(playlist) => playlist,
+        None => {
+            return failure(
+                StatusCode::NOT_FOUND,
+                &format!("no playlist is called {}", &delivery.playlist)
+            );
+        }
+    };
+
+    let mut changed = 0;
+    for webhook in playlist.webhooks.iter().filter(|w| w.destination == delivery.destination) {
+        for target in webhook.targets() {
+            let wanted = delivery.target.as_ref();
+            if wanted.is_some_and(|wanted| *wanted != target && *wanted != redact(&target)) {
+                continue;
+            }
+
+            let key = DeliveryKey {
+                video: &delivery.video,
+                playlist: &playlist.id,
+                destination: &webhook.destination,
+                target: &target,
+            };
+            let result = if requeue {
+                admin.store.requeue_delivery(&key).await
+            } else {
+                admin.store.skip_delivery(&key).await
+            };
+            match result {
+                Ok(true) => {
+                    changed += 1;
+                }
+                Ok(false) => {}
+                Err(e) => {
+                    return internal(e);
+                }
+            }
+        }
+    }
+
+    // A video with nothing left to send is done in this playlist
+    if !requeue && changed > 0 {
+        let done = admin.store
+            .pending_count(&delivery.video, &playlist.id).await
+            .map(|pending| pending == 0);
+        let marked = match done {
+            Ok(true) => admin.store.mark_hooked(&delivery.video, &playlist.id).await,
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = marked {
+            return internal(e);
+        }
+    }
+
+    if changed == 0 {
+        return failure(StatusCode::NOT_FOUND, "no matching delivery");
+    }
+    let verb = if requeue { "Requeued" } else { "Skipped" };
+    tracing::info!("{} {} deliveries of {} through the admin API", verb, changed, &delivery.video);
+
+    Json(Changed { changed }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Entry;
+    use crate::delivery::DeliveryStatus;
+    use crate::store::MemoryStore;
+    use axum::body::Body;
+    use tokio::sync::{ mpsc, watch };
+    use tower::ServiceExt;
+
+    const ANNOUNCEMENTS: &str = "https://discord.com/api/webhooks/1/token";
+
+    async fn admin() -> (Router, Arc<MemoryStore>, mpsc::UnboundedReceiver<String>) {
+        let playlist: Playlist = serde_yaml::from_str(&format!(
+            "id: PL1
+name: uploads
+webhooks:
+  - name: announcements
+    destination: discord
+    urls: [{}]",
+            ANNOUNCEMENTS
+        )).unwrap();
+        let store = Arc::new(MemoryStore::default());
+        let entry = Entry {
+            title: "First".to_string(),
+            id: "yt:video:a".to_string(),
+            published: "2024-01-01T00:00:00+00:00".to_string(),
+            updated: "2024-01-01T00:00:00+00:00".to_string(),
+            author: None,
+        };
+        store.upsert_entry("PL1", &entry, false).await.unwrap();
+        let (_, playlists) = watch::channel(Arc::new(vec![playlist]));
+        let (wake, woken) = mpsc::unbounded_channel();
+        let admin = Arc::new(Admin {
+            config: serde_yaml::from_str("token: secret").unwrap(),
+            playlists,
+            store: store.clone(),
+            wake,
+        });
+        (router(admin), store, woken)
+    }
+
+    fn post(uri: &str, token: Option<&str>, body: Option<&str>) -> Request {
+        let mut request = Request::post(uri);
+        if let Some(token) = token {
+            request = request.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+        match body {
+            Some(body) => request.header(header::CONTENT_TYPE, "application/json").body(Body::from(body.to_string())),
+            None => request.body(Body::empty()),
+        }.unwrap()
+    }
+
+    async fn status(store: &MemoryStore) -> String {
+        store.deliveries("a").await.unwrap()[0].status.clone()
+    }
+
+    #[tokio::test]
+    async fn needs_the_token() {
+        let (app, store, _woken) = admin().await;
+
+        let response = app.clone().oneshot(post("/playlists/PL1/pause", None, None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.clone().oneshot(post("/playlists/PL1/pause", Some("wrong"), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = app.oneshot(Request::get("/playlists").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert!(store.pauses().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pauses_and_resumes_playlists() {
+        let (app, store, mut woken) = admin().await;
+
+        let response = app.clone().oneshot(post("/playlists/PL1/pause", Some("secret"), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.pauses().await.unwrap().iter().any(|p| p.is(PauseScope::Playlist, "PL1")));
+
+        let response = app.clone().oneshot(post("/playlists/PL1/resume", Some("secret"), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert!(store.pauses().await.unwrap().is_empty());
+        assert_eq!(woken.recv().await.unwrap(), "PL1");
+
+        let response = app.oneshot(post("/playlists/PL2/pause", Some("secret"), None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn skips_and_requeues_deliveries() {
+        let (app, store, _woken) = admin().await;
+        let key = DeliveryKey { video: "a", playlist: "PL1", destination: &WebhookType::Discord, target: ANNOUNCEMENTS };
+        store.enqueue(&key).await.unwrap();
+        let delivery = r#"{ "video": "a", "playlist": "PL1", "destination": "discord" }"#;
+
+        let response = app.clone().oneshot(post("/deliveries/skip", Some("secret"), Some(delivery))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(status(&store).await, DeliveryStatus::Skipped.as_str());
+        assert!(store.unhooked_for_playlist("PL1").await.unwrap().is_empty());
+
+        // The target can be given redacted, as /videos lists it
+        let delivery = format!(
+            r#"{{ "video": "a", "playlist": "PL1", "destination": "discord", "target": "{}" }}"#,
+            redact(ANNOUNCEMENTS)
+        );
+        let response = app.oneshot(post("/deliveries/requeue", Some("secret"), Some(&delivery))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(status(&store).await, DeliveryStatus::Pending.as_str());
+    }
+}
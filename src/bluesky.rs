@@ -0,0 +1,210 @@
+use anyhow::anyhow;
+use atrium_api::app::bsky::embed::external::External;
+use atrium_api::app::bsky::embed::external::ExternalData;
+use atrium_api::app::bsky::embed::external::Main;
+use atrium_api::app::bsky::embed::external::MainData;
+use atrium_api::app::bsky::feed::post::RecordData;
+use atrium_api::app::bsky::feed::post::RecordEmbedRefs;
+use atrium_api::app::bsky::feed::post::ReplyRefData;
+use atrium_api::com::atproto::repo::strong_ref;
+use atrium_api::types::string::Cid;
+use atrium_api::types::string::Datetime as BskyDateTime;
+use atrium_api::types::string::Language;
+use atrium_api::types::BlobRef;
+use atrium_api::types::Union;
+use bsky_sdk::BskyAgent;
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+
+use crate::delivery::Receipt;
+use crate::services::ServicesConfig;
+use crate::store::Video;
+use crate::Credentials;
+
+/// Builds the announcement post for a video, with its thumbnail if one was uploaded
+pub fn render(services: &ServicesConfig, v: &Video, thumb: Option<BlobRef>) -> RecordData {
+    RecordData {
+        created_at: BskyDateTime::now(),
+        embed: Some(
+            Union::Refs(
+                RecordEmbedRefs::AppBskyEmbedExternalMain(
+                    Box::new(Main {
+                        data: MainData {
+                            external: External {
+                                data: ExternalData {
+                                    title: v.title.clone(),
+                                    description: v.author.clone(),
+                                    uri: services.watch(&v.id),
+                                    thumb,
+                                },
+                                extra_data: ipld_core::ipld::Ipld::Null,
+                            },
+                        },
+                        extra_data: ipld_core::ipld::Ipld::Null,
+                    })
+                )
+            )
+        ),
+        entities: None,
+        facets: None,
+        labels: None,
+        langs: Some(vec![Language::new(String::from("en-US")).unwrap()]),
+        reply: None,
+        tags: None,
+        text: v.title.clone(),
+    }
+}
+
+/// Posts to Bluesky, keeping one logged in session per account so a long-running relay
+/// doesn't log in for every post
+pub struct BlueskySender {
+    client: reqwest::Client,
+    services: ServicesConfig,
+    agents: Mutex<HashMap<String, Arc<BskyAgent>>>,
+}
+
+impl BlueskySender {
+    pub fn new(client: reqwest::Client, services: ServicesConfig) -> Self {
+        Self {
+            client,
+            services,
+            agents: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The session for an account, logging in if there isn't one yet
+    async fn agent(&self, credentials: &Credentials) -> anyhow::Result<Arc<BskyAgent>> {
+        if let Some(agent) = self.agents.lock().unwrap().get(&credentials.username) {
+            return Ok(agent.clone());
+        }
+
+        let config = bsky_sdk::agent::config::Config {
+            endpoint: self.services.bluesky.clone(),
+            ..Default::default()
+        };
+        let agent = match BskyAgent::builder().config(config).build().await {
+            Ok(agent) => agent,
+            Err(e) => {
+                return Err(anyhow!("{:?}", e));
+            }
+        };
+
+        if let Err(e) = agent.login(&credentials.username, &credentials.password).await {
+            return Err(anyhow!("{:?}", e));
+        }
+
+        let agent = Arc::new(agent);
+        self.agents.lock().unwrap().insert(credentials.username.clone(), agent.clone());
+        Ok(agent)
+    }
+
+    /// Drops the session of an account after a failure, so the next attempt logs in again
+    fn forget(&self, credentials: &Credentials) {
+        self.agents.lock().unwrap().remove(&credentials.username);
+    }
+
+    /// Posts the announcement for a video. The receipt carries the post's URI and CID
+    pub async fn post(&self, credentials: &Credentials, v: &Video) -> anyhow::Result<Receipt> {
+        let agent = self.agent(credentials).await?;
+
+        let blob = match self.thumbnail(&v.id).await {
+            Some(thumbnail) =>
+                match agent.api.com.atproto.repo.upload_blob(thumbnail).await {
+                    Ok(blob) => Some(blob.blob.clone()),
+                    Err(e) => {
+                        self.forget(credentials);
+                        return Err(anyhow!("{:?}", e));
+                    }
+                }
+            None => None,
+        };
+
+        match agent.create_record(render(&self.services, v, blob)).await {
+            Ok(result) => {
+                tracing::info!("{}", &format!("Published Video: {} to Bluesky!", &v.title));
+                Ok(Receipt {
+                    message_id: Some(result.uri.clone()),
+                    thread_id: None,
+                    record_cid: Some(result.cid.as_ref().to_string()),
+                })
+            }
+            Err(e) => {
+                self.forget(credentials);
+                Err(anyhow!("{:?}", e))
+            }
+        }
+    }
+
+    /// The largest thumbnail YouTube has for a video. Not every video has a maxres one, so the hq one
+    /// is tried next. A post without a thumbnail still goes out, so `None` when neither can be fetched
+    async fn thumbnail(&self, id: &str) -> Option<Vec<u8>> {
+        let maxres = self.services.thumbnail(id);
+        let hq = maxres.replace("/maxresdefault.jpg", "/hqdefault.jpg");
+
+        for url in [maxres, hq] {
+            match self.download(&url).await {
+                Ok(thumbnail) => {
+                    return Some(thumbnail);
+                }
+                Err(e) => tracing::debug!("Unable to fetch the thumbnail {}: {:?}", url, e),
+            }
+        }
+
+        tracing::warn!("No thumbnail could be fetched for {}, posting it without one", id);
+        None
+    }
+
+    async fn download(&self, url: &str) -> anyhow::Result<Vec<u8>> {
+        Ok(self.client.get(url).send().await?.error_for_status()?.bytes().await?.to_vec())
+    }
+
+    /// Deletes a post by its at:// URI
+    pub async fn delete(&self, credentials: &Credentials, uri: &str) -> anyhow::Result<()> {
+        let agent = self.agent(credentials).await?;
+        if let Err(e) = agent.delete_record(uri).await {
+            self.forget(credentials);
+            return Err(anyhow!("{:?}", e));
+        }
+
+        Ok(())
+    }
+
+    /// Posts are immutable, so "editing" one means replying to it
+    pub async fn reply(
+        &self,
+        credentials: &Credentials,
+        uri: &str,
+        cid: &str,
+        text: &str
+    ) -> anyhow::Result<()> {
+        let agent = self.agent(credentials).await?;
+        let parent = strong_ref::MainData {
+            cid: Cid::new(cid.parse()?),
+            uri: uri.to_string(),
+        };
+
+        let record = RecordData {
+            created_at: BskyDateTime::now(),
+            embed: None,
+            entities: None,
+            facets: None,
+            labels: None,
+            langs: Some(vec![Language::new(String::from("en-US")).unwrap()]),
+            reply: Some(
+                ReplyRefData {
+                    parent: parent.clone().into(),
+                    root: parent.into(),
+                }.into()
+            ),
+            tags: None,
+            text: text.to_string(),
+        };
+
+        if let Err(e) = agent.create_record(record).await {
+            self.forget(credentials);
+            return Err(anyhow!("{:?}", e));
+        }
+
+        Ok(())
+    }
+}
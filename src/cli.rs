@@ -0,0 +1,109 @@
+use clap::{ Parser, Subcommand };
+use std::path::PathBuf;
+
+use crate::config::ConfigFormat;
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// The configuration file
+    #[arg(long, global = true, default_value = "data.hcl")]
+    pub config: PathBuf,
+    /// The language the configuration file is written in, when its extension doesn't say
+    #[arg(long, global = true, value_enum)]
+    pub config_format: Option<ConfigFormat>,
+    /// The database to use, overriding `storage.dsn`: a file for sqlite, a connection string for postgres
+    #[arg(long, global = true)]
+    pub db: Option<String>,
+    /// With run or daemon: do a single pass that prints every payload it would send as JSON lines,
+    /// without sending anything or changing any state
+    #[arg(long, global = true)]
+    pub dry_run: bool,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Do a single pass over every playlist and exit, for running from cron. Running without
+    /// a subcommand does the same
+    Run {
+        /// Accepted for scripts written when `run` kept running; `run` always does a single pass
+        #[arg(long, hide = true)]
+        once: bool,
+    },
+    /// Keep running, polling every playlist on its own interval
+    Daemon,
+    /// Check the configuration file, report every problem in it, and exit
+    Validate,
+    /// Print the JSON Schema of the configuration file, for editors and pre-commit hooks
+    Schema,
+    /// List the known videos and the state of their deliveries
+    List {
+        /// Only list videos in this playlist
+        #[arg(long)]
+        playlist: Option<String>,
+    },
+    /// Record a video as delivered without sending anything, for announcements made by hand
+    #[command(name = "mark-delivered")]
+    MarkDelivered {
+        video: String,
+        /// Only for this playlist
+        #[arg(long)]
+        playlist: Option<String>,
+        /// Only for this destination (discord, bluesky)
+        #[arg(long)]
+        destination: Option<String>,
+    },
+    /// Send a video again, whatever happened to its deliveries before
+    Replay {
+        video: String,
+        /// Only resend to this destination (discord, bluesky)
+        #[arg(long)]
+        destination: Option<String>,
+    },
+    /// Send a synthetic announcement to the webhook with this name
+    #[command(name = "test-destination")]
+    TestDestination {
+        name: String,
+        /// The video the announcement links to
+        #[arg(long, default_value = "jNQXAC9IVRw")]
+        video: String,
+    },
+    /// Record every video currently in a playlist as delivered, so only newer videos are announced
+    Seed {
+        /// The playlist id, as configured
+        playlist: String,
+    },
+    /// Inspect or requeue deliveries that exhausted their retries
+    #[command(subcommand, name = "dead-letter")]
+    DeadLetter(DeadLetterCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DeadLetterCommand {
+    /// List every dead-lettered delivery
+    List,
+    /// Move dead-lettered deliveries back to pending
+    Requeue {
+        /// Only requeue deliveries for this video id
+        #[arg(long)]
+        video: Option<String>,
+        /// Only requeue deliveries for this destination (discord, bluesky)
+        #[arg(long)]
+        destination: Option<String>,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_still_accepts_once() {
+        for args in [&["relay", "run"][..], &["relay", "run", "--once"]] {
+            let cli = Cli::try_parse_from(args).unwrap();
+            assert!(matches!(cli.command, Some(Command::Run { .. })), "{:?}", args);
+        }
+    }
+}
@@ -0,0 +1,467 @@
+use anyhow::anyhow;
+use chrono::Utc;
+use std::collections::HashMap;
+
+use crate::bluesky::BlueskySender;
+use crate::delivery::{ DeliveryKey, DeliveryStatus, Receipt };
+use crate::discord::DiscordSender;
+use crate::secrets::redact;
+use crate::store::{ self, PauseScope, Video, VideoStore };
+use crate::youtube::{ Fetched, YouTube };
+use crate::{ bluesky, deliver, Config, Playlist, WebhookType };
+
+/// Prints every video, then each of its deliveries and their state
+pub async fn list(store: &dyn VideoStore, playlist: Option<&str>) -> anyhow::Result<()> {
+    for v in store.videos(playlist).await? {
+        println!("{}\t{}\t{}\thooked={}\t{}", v.id, v.playlist, v.timestamp, v.hooked, v.title);
+        let deliveries = store.deliveries(&v.id).await?;
+        for d in deliveries.iter().filter(|d| d.playlist == v.playlist) {
+            println!(
+                "\t{}\t{}\t{}\tattempts={}\t{}",
+                d.destination,
+                redact(&d.target),
+                d.status,
+                d.attempts,
+                d.last_error.clone().unwrap_or_default()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Records a video as delivered to every configured sink without sending anything.
+/// Deliveries that already went out, or were skipped or retracted, are left alone
+pub async fn mark_delivered(
+    config: &Config,
+    store: &dyn VideoStore,
+    video: &str,
+    playlist: Option<&str>,
+    destination: Option<&str>
+) -> anyhow::Result<usize> {
+    let mut marked = 0;
+    for v in store.videos(playlist).await?.iter().filter(|v| v.id == video) {
+        let playlist = match config.playlist.iter().find(|p| p.id == v.playlist) {
+            Some(playlist) => playlist,
+            None => {
+                continue;
+            }
+        };
+        let existing = store.deliveries(&v.id).await?;
+
+        for webhook in &playlist.webhooks {
+            if destination.is_some_and(|destination| webhook.destination.as_str() != destination) {
+                continue;
+            }
+
+            for target in webhook.targets() {
+                let status = existing
+                    .iter()
+                    .find(|d| {
+                        d.playlist == playlist.id &&
+                            d.destination == webhook.destination.as_str() &&
+                            d.target == target
+                    })
+                    .map(|d| d.status.as_str());
+                let outstanding = status.is_none_or(|status| {
+                    status == DeliveryStatus::Pending.as_str() ||
+                        status == DeliveryStatus::Dead.as_str()
+                });
+                if !outstanding {
+                    continue;
+                }
+
+                let key = DeliveryKey {
+                    video: &v.id,
+                    playlist: &playlist.id,
+                    destination: &webhook.destination,
+                    target: &target,
+                };
+                store.enqueue(&key).await?;
+                store.mark_delivered(&key, &Receipt::default()).await?;
+                marked += 1;
+            }
+        }
+
+        // The other destinations may not have a delivery yet. Left unhooked, the next pass
+        // enqueues theirs and hooks the video once they went out
+        if destination.is_none() && store.pending_count(&v.id, &playlist.id).await? == 0 {
+            store.mark_hooked(&v.id, &playlist.id).await?;
+        }
+    }
+
+    Ok(marked)
+}
+
+/// Sends a video again to every configured sink of every playlist it is in,
+/// or only to one destination. Returns how many deliveries were replayed
+pub async fn replay(
+    config: &Config,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &dyn VideoStore,
+    video: &str,
+    destination: Option<&str>
+) -> anyhow::Result<usize> {
+    let memberships: Vec<Video> = store
+        .videos(None).await?
+        .into_iter()
+        .filter(|v| v.id == video)
+        .collect();
+    if memberships.is_empty() {
+        return Err(anyhow!("Video {} is not known", video));
+    }
+
+    // Seeded videos never had deliveries, so make sure every sink has one to replay
+    for v in &memberships {
+        if let Some(playlist) = config.playlist.iter().find(|p| p.id == v.playlist) {
+            for webhook in &playlist.webhooks {
+                if destination.is_some_and(|destination| webhook.destination.as_str() != destination) {
+                    continue;
+                }
+
+                for target in webhook.targets() {
+                    let key = DeliveryKey {
+                        video: &v.id,
+                        playlist: &playlist.id,
+                        destination: &webhook.destination,
+                        target: &target,
+                    };
+                    store.enqueue(&key).await?;
+                }
+            }
+        }
+    }
+
+    let replayed = store.replay(video, destination).await?;
+    for v in &memberships {
+        if let Some(playlist) = config.playlist.iter().find(|p| p.id == v.playlist) {
+            deliver(config, discord, bluesky, store, playlist, v).await?;
+        }
+    }
+
+    Ok(replayed)
+}
+
+/// Sends a synthetic announcement to every sink of the webhook called `name`, without touching the
+/// database. Groups aren't mentioned, so a test doesn't ping anyone
+pub async fn test_destination(
+    config: &Config,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    name: &str,
+    video: &str
+) -> anyhow::Result<()> {
+    let webhook = config.playlist
+        .iter()
+        .flat_map(|p| p.webhooks.iter())
+        .find(|w| w.name.as_deref() == Some(name));
+    let mut webhook = match webhook {
+        Some(webhook) => webhook.clone(),
+        None => {
+            return Err(anyhow!("No webhook is named {}", name));
+        }
+    };
+    webhook.groups = None;
+
+    let v = Video {
+        id: video.to_string(),
+        playlist: String::new(),
+        title: "Test announcement from the relay".to_string(),
+        author: config.author.name.clone(),
+        timestamp: Utc::now().to_rfc3339(),
+        hooked: 0,
+        updated: String::new(),
+    };
+
+    for (i, target) in webhook.targets().iter().enumerate() {
+        let result = match webhook.destination {
+            WebhookType::Discord => {
+                let message = DiscordSender::render(config, &webhook, &v);
+                discord.send(target, &message, webhook.is_forum.unwrap_or(false)).await
+            }
+            WebhookType::BlueSky =>
+                match &webhook.credentials {
+                    Some(credentials) => bluesky.post(credentials, &v).await,
+                    None => Err(anyhow!("The bluesky webhook has no credentials")),
+                }
+        };
+
+        match result {
+            Ok(_) => println!("Sent a test announcement to {} #{}", webhook.destination.as_str(), i + 1),
+            Err(e) => {
+                return Err(anyhow!("{} #{} failed: {:?}", webhook.destination.as_str(), i + 1, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Works out what a pass would send without writing anything: the feed entries a pass would ingest,
+/// plus the videos already waiting, are rendered for every sink that hasn't had them yet.
+/// Each payload is printed as a JSON line. Bluesky records go without the thumbnail, which a real
+/// post uploads first
+pub async fn dry_run(
+    config: &Config,
+    youtube: &YouTube,
+    store: &dyn VideoStore
+) -> anyhow::Result<()> {
+    for line in outbound(config, youtube, store).await? {
+        println!("{}", line);
+    }
+
+    Ok(())
+}
+
+/// The lines `dry_run` prints
+async fn outbound(
+    config: &Config,
+    youtube: &YouTube,
+    store: &dyn VideoStore
+) -> anyhow::Result<Vec<serde_json::Value>> {
+    let mut lines = Vec::new();
+    let dedupe = config.dedupe.unwrap_or(false);
+    let services = config.services();
+    // Only what a real pass would send: paused playlists aren't polled, and paused destinations wait
+    let pauses = store.pauses().await?;
+    let playlists: Vec<Playlist> = config.playlist
+        .iter()
+        .filter(|p| !pauses.iter().any(|pause| pause.is(PauseScope::Playlist, &p.id)))
+        .cloned()
+        .collect();
+    let feeds = youtube.fetch_feeds(&playlists, None).await;
+
+    for (playlist, feed) in playlists.iter().zip(feeds) {
+        let known = store.is_known_playlist(&playlist.id).await?;
+        let seed = !known && !playlist.backfill.unwrap_or(true);
+        let memberships: HashMap<String, Video> = store
+            .videos(Some(&playlist.id)).await?
+            .into_iter()
+            .map(|v| (v.id.clone(), v))
+            .collect();
+
+        let mut pending: Vec<Video> = memberships
+            .values()
+            .filter(|v| v.hooked == 0)
+            .cloned()
+            .collect();
+        if let Some(Fetched::Changed(feed, _)) = feed {
+            // A playlist that would be seeded announces nothing from its feed
+            for entry in feed.entry.iter().filter(|_| !seed) {
+                let id = store::video_id(entry);
+                if !memberships.contains_key(&id) {
+                    pending.push(Video {
+                        id,
+                        playlist: playlist.id.clone(),
+                        title: entry.title.clone(),
+                        author: entry.author.as_ref().map_or(String::new(), |author| author.name.clone()),
+                        timestamp: entry.published.clone(),
+                        hooked: 0,
+                        updated: entry.updated.clone(),
+                    });
+                }
+            }
+        }
+        pending.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        for v in &pending {
+            let deliveries = store.deliveries(&v.id).await?;
+            for webhook in &playlist.webhooks {
+                if webhook.is_paused(&pauses) {
+                    continue;
+                }
+
+                for target in webhook.targets() {
+                    let delivery = deliveries
+                        .iter()
+                        .find(|d| {
+                            d.playlist == playlist.id &&
+                                d.destination == webhook.destination.as_str() &&
+                                d.target == target
+                        });
+                    // A delivery that failed waits out its backoff
+                    let sends = match delivery {
+                        Some(_) => {
+                            let key = DeliveryKey {
+                                video: &v.id,
+                                playlist: &playlist.id,
+                                destination: &webhook.destination,
+                                target: &target,
+                            };
+                            store.is_due(&key).await?
+                        }
+                        None =>
+                            !dedupe ||
+                                !store.has_delivery(&v.id, &webhook.destination, &target).await?,
+                    };
+                    if !sends {
+                        continue;
+                    }
+
+                    let payload = match webhook.destination {
+                        WebhookType::Discord =>
+                            serde_json::to_value(DiscordSender::render(config, webhook, v))?,
+                        WebhookType::BlueSky => serde_json::to_value(bluesky::render(&services, v, None))?,
+                    };
+                    lines.push(
+                        serde_json::json!({
+                            "playlist": &playlist.id,
+                            "video": &v.id,
+                            "destination": webhook.destination.as_str(),
+                            "target": redact(&target),
+                            "payload": payload,
+                        })
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Entry;
+    use crate::retry::RetryPolicy;
+    use crate::services::ServicesConfig;
+    use crate::store::MemoryStore;
+    use crate::youtube::FetchConfig;
+
+    const ANNOUNCEMENTS: &str = "https://discord.com/api/webhooks/1/token";
+    const ARCHIVE: &str = "https://discord.com/api/webhooks/2/token";
+
+    fn config() -> Config {
+        serde_yaml::from_str(&format!(
+            "author: {{ name: Author, url: https://www.youtube.com/@author, icon: https://example.com/icon.png }}
+bot: {{ name: Bot, url: https://example.com, icon: https://example.com/bot.png }}
+playlist:
+  - id: PL1
+    name: uploads
+    webhooks:
+      - name: announcements
+        destination: discord
+        urls: [{}]
+      - name: archive
+        destination: discord
+        urls: [{}]
+      - name: sky
+        destination: bluesky
+        credentials: {{ username: alaydriem.com, password: secret }}",
+            ANNOUNCEMENTS,
+            ARCHIVE
+        )).unwrap()
+    }
+
+    fn key<'a>(destination: &'a WebhookType, target: &'a str) -> DeliveryKey<'a> {
+        DeliveryKey { video: "a", playlist: "PL1", destination, target }
+    }
+
+    async fn store() -> MemoryStore {
+        let store = MemoryStore::default();
+        let entry = Entry {
+            title: "First".to_string(),
+            id: "yt:video:a".to_string(),
+            published: "2024-01-01T00:00:00+00:00".to_string(),
+            updated: "2024-01-01T00:00:00+00:00".to_string(),
+            author: None,
+        };
+        store.upsert_entry("PL1", &entry, false).await.unwrap();
+        store
+    }
+
+    fn status(deliveries: &[crate::delivery::Delivery], target: &str) -> String {
+        deliveries.iter().find(|d| d.target == target).unwrap().status.clone()
+    }
+
+    #[tokio::test]
+    async fn mark_delivered_settles_every_outstanding_delivery() {
+        let store = store().await;
+        store.enqueue(&key(&WebhookType::Discord, ANNOUNCEMENTS)).await.unwrap();
+
+        let marked = mark_delivered(&config(), &store, "a", None, None).await.unwrap();
+        assert_eq!(marked, 3);
+        let deliveries = store.deliveries("a").await.unwrap();
+        assert_eq!(deliveries.len(), 3);
+        assert!(deliveries.iter().all(|d| d.status == DeliveryStatus::Delivered.as_str()));
+        assert!(store.unhooked_for_playlist("PL1").await.unwrap().is_empty());
+
+        // Nothing is left to mark the second time
+        assert_eq!(mark_delivered(&config(), &store, "a", None, None).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn mark_delivered_for_one_destination_leaves_the_video_unhooked() {
+        let store = store().await;
+
+        let marked = mark_delivered(&config(), &store, "a", None, Some("bluesky")).await.unwrap();
+        assert_eq!(marked, 1);
+        assert_eq!(status(&store.deliveries("a").await.unwrap(), "alaydriem.com"), DeliveryStatus::Delivered.as_str());
+        assert_eq!(store.unhooked_for_playlist("PL1").await.unwrap(), vec!["a".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn replay_only_requeues_the_destination_asked_for() {
+        let config = config();
+        let store = store().await;
+        mark_delivered(&config, &store, "a", None, None).await.unwrap();
+        // Paused webhooks keep what is requeued pending, so nothing is actually sent
+        for name in ["announcements", "archive", "sky"] {
+            store.set_paused(PauseScope::Destination, name, true).await.unwrap();
+        }
+
+        let client = reqwest::Client::new();
+        let discord = DiscordSender::new(client.clone(), "https://discord.test/api".to_string());
+        let bluesky = BlueskySender::new(client, config.services());
+        let replayed = replay(&config, &discord, &bluesky, &store, "a", Some("discord")).await.unwrap();
+
+        assert_eq!(replayed, 2);
+        let deliveries = store.deliveries("a").await.unwrap();
+        assert_eq!(status(&deliveries, ANNOUNCEMENTS), DeliveryStatus::Pending.as_str());
+        assert_eq!(status(&deliveries, ARCHIVE), DeliveryStatus::Pending.as_str());
+        assert_eq!(status(&deliveries, "alaydriem.com"), DeliveryStatus::Delivered.as_str());
+    }
+
+    #[tokio::test]
+    async fn dry_run_leaves_out_what_a_pass_would_not_send() {
+        let mut config = config();
+        let mut paused = config.playlist[0].clone();
+        paused.id = "PL2".to_string();
+        paused.webhooks.truncate(1);
+        paused.webhooks[0].name = None;
+        config.playlist.push(paused);
+        // Feeds can't be fetched, so only what the store already holds is left to send
+        config.services = Some(ServicesConfig {
+            feeds: "http://127.0.0.1:1/feeds".to_string(),
+            ..ServicesConfig::default()
+        });
+
+        let store = store().await;
+        let backed_off = RetryPolicy { max_attempts: 5, base_delay: 3600, max_delay: 3600, jitter: 0.0 };
+        store.enqueue(&key(&WebhookType::Discord, ANNOUNCEMENTS)).await.unwrap();
+        store.mark_failed(&key(&WebhookType::Discord, ANNOUNCEMENTS), "boom", &backed_off).await.unwrap();
+        store.set_paused(PauseScope::Destination, "archive", true).await.unwrap();
+        store.upsert_entry(
+            "PL2",
+            &Entry {
+                title: "Second".to_string(),
+                id: "yt:video:b".to_string(),
+                published: "2024-01-02T00:00:00+00:00".to_string(),
+                updated: "2024-01-02T00:00:00+00:00".to_string(),
+                author: None,
+            },
+            false
+        ).await.unwrap();
+        store.set_paused(PauseScope::Playlist, "PL2", true).await.unwrap();
+
+        let youtube = YouTube::new(reqwest::Client::new(), FetchConfig::default(), config.services());
+        let lines = outbound(&config, &youtube, &store).await.unwrap();
+
+        assert_eq!(lines.len(), 1, "{:?}", lines);
+        assert_eq!(lines[0]["video"], "a");
+        assert_eq!(lines[0]["destination"], "bluesky");
+        assert_eq!(lines[0]["target"], "alaydriem.com");
+    }
+}
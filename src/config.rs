@@ -0,0 +1,542 @@
+use anyhow::anyhow;
+use clap::ValueEnum;
+use reqwest::Url;
+use schemars::schema::{ RootSchema, Schema };
+use serde::de::value::{ MapAccessDeserializer, SeqAccessDeserializer };
+use serde::de::{ MapAccess, SeqAccess, Visitor };
+use serde::{ Deserialize, Deserializer };
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::str::FromStr;
+use tracing::Level;
+
+use crate::secrets::{ self, Secrets };
+use crate::store::StorageBackend;
+use crate::{ Config, WebhookType };
+
+/// The languages a configuration file can be written in
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ConfigFormat {
+    Hcl,
+    Yaml,
+    Toml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Picks the format from the file extension: .hcl, .yaml/.yml, .toml or .json
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+        match extension.as_str() {
+            "hcl" => Some(ConfigFormat::Hcl),
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "toml" => Some(ConfigFormat::Toml),
+            "json" => Some(ConfigFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Reads and parses the configuration file, then resolves its secrets. Without an explicit `format`,
+/// the file extension decides. Errors name the file, and the line and column when the parser reports them
+pub fn load(path: &Path, format: Option<ConfigFormat>, secrets: &Secrets) -> anyhow::Result<Config> {
+    let format = match format.or_else(|| ConfigFormat::from_path(path)) {
+        Some(format) => format,
+        None => {
+            return Err(
+                anyhow!(
+                    "{}: unknown configuration format, use a .hcl, .yaml, .toml or .json file or pass --config-format",
+                    path.display()
+                )
+            );
+        }
+    };
+    let data = fs::read_to_string(path).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+
+    let mut config = parse(&data, format).map_err(|e| anyhow!("{}{}", path.display(), e))?;
+
+    secrets::resolve(&mut config, secrets).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+    Ok(config)
+}
+
+/// Parses a configuration document. The error starts with `:line:column` when the position is known
+fn parse(data: &str, format: ConfigFormat) -> Result<Config, String> {
+    match format {
+        ConfigFormat::Hcl => hcl::from_str(data).map_err(|e| format!(": {}", e)),
+        ConfigFormat::Yaml =>
+            serde_yaml::from_str(data).map_err(|e| {
+                match e.location() {
+                    Some(location) => format!(":{}:{}: {}", location.line(), location.column(), e),
+                    None => format!(": {}", e),
+                }
+            }),
+        ConfigFormat::Toml =>
+            toml::from_str(data).map_err(|e| {
+                match e.span() {
+                    Some(span) => {
+                        let (line, column) = position(data, span.start);
+                        format!(":{}:{}: {}", line, column, e.message())
+                    }
+                    None => format!(": {}", e.message()),
+                }
+            }),
+        ConfigFormat::Json =>
+            serde_json::from_str(data).map_err(|e| format!(":{}:{}: {}", e.line(), e.column(), e)),
+    }
+}
+
+/// A list, or a single object. HCL parses a block that appears once as an object and repeated
+/// blocks as a list, so `playlist` and `webhooks` take either
+pub fn one_or_many<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    struct OneOrMany<T>(PhantomData<T>);
+
+    impl<'de, T: Deserialize<'de>> Visitor<'de> for OneOrMany<T> {
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.write_str("a list or a single block")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, seq: A) -> Result<Vec<T>, A::Error> {
+            Vec::deserialize(SeqAccessDeserializer::new(seq))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Vec<T>, A::Error> {
+            Ok(vec![T::deserialize(MapAccessDeserializer::new(map))?])
+        }
+    }
+
+    deserializer.deserialize_any(OneOrMany(PhantomData))
+}
+
+/// The 1-based line and column of a byte offset
+fn position(data: &str, offset: usize) -> (usize, usize) {
+    let before = &data[..offset.min(data.len())];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|c| *c != '\n').count() + 1;
+    (line, column)
+}
+
+/// The JSON Schema of the configuration, for editor completion and pre-commit checks. On top of
+/// what the types say, each destination requires the fields it can't send without
+pub fn schema() -> RootSchema {
+    let mut schema = schemars::schema_for!(Config);
+
+    if let Some(Schema::Object(webhook)) = schema.definitions.get_mut("Webhook") {
+        let destinations = serde_json::json!([
+            {
+                "if": { "properties": { "destination": { "const": "discord" } } },
+                "then": { "anyOf": [{ "required": ["urls"] }, { "required": ["urls_file"] }] }
+            },
+            {
+                "if": { "properties": { "destination": { "const": "bluesky" } } },
+                "then": { "required": ["credentials"] }
+            }
+        ]);
+        webhook.subschemas().all_of = Some(serde_json::from_value(destinations).expect("destination schemas are valid"));
+    }
+    if let Some(Schema::Object(credentials)) = schema.definitions.get_mut("Credentials") {
+        let password = serde_json::json!([{ "required": ["password"] }, { "required": ["password_file"] }]);
+        credentials.subschemas().one_of = Some(serde_json::from_value(password).expect("password schemas are valid"));
+    }
+
+    schema
+}
+
+/// Something wrong with a configuration that parsed
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    /// Where the problem is, as in `playlist[0].webhooks[1].urls[0]`
+    pub path: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// Fails with every problem `validate` finds, one per line under the file name
+pub fn check(path: &Path, config: &Config) -> anyhow::Result<()> {
+    let problems = validate(config);
+    if problems.is_empty() {
+        return Ok(());
+    }
+
+    let lines: Vec<String> = problems
+        .iter()
+        .map(|problem| format!("  {}", problem))
+        .collect();
+    let noun = if problems.len() == 1 { "problem" } else { "problems" };
+    Err(anyhow!("{} has {} {}:\n{}", path.display(), problems.len(), noun, lines.join("\n")))
+}
+
+/// Checks what parsing can't, before anything touches the network: the fields each destination needs,
+/// URL shapes, duplicate playlists and webhook names, log levels, Bluesky handles and numeric ranges.
+/// Every problem is returned, not just the first. Messages never quote secret values
+pub fn validate(config: &Config) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let mut problem = |path: String, message: &str| {
+        problems.push(Problem { path, message: message.to_string() });
+    };
+
+    if let Some(level) = &config.log_level {
+        if Level::from_str(level).is_err() {
+            problem("log_level".to_string(), "must be one of trace, debug, info, warn or error");
+        }
+    }
+
+    let services = config.services();
+    for (name, url) in services.urls() {
+        if let Err(e) = services.check_url(url) {
+            problem(format!("services.{}", name), &e.to_string());
+        }
+    }
+
+    let mut playlists = HashMap::new();
+    let mut webhook_names = HashMap::new();
+    for (i, playlist) in config.playlist.iter().enumerate() {
+        let path = format!("playlist[{}]", i);
+        if playlist.id.is_empty() {
+            problem(format!("{}.id", path), "must not be empty");
+        } else if let Some(first) = playlists.insert(playlist.id.as_str(), i) {
+            problem(format!("{}.id", path), &format!("{} is already the id of playlist[{}]", &playlist.id, first));
+        }
+        if playlist.name.is_empty() {
+            problem(format!("{}.name", path), "must not be empty");
+        }
+        if playlist.poll_interval == Some(0) {
+            problem(format!("{}.poll_interval", path), "must be at least 1 second");
+        }
+
+        for (j, webhook) in playlist.webhooks.iter().enumerate() {
+            let path = format!("{}.webhooks[{}]", path, j);
+            if let Some(name) = &webhook.name {
+                if let Some(first) = webhook_names.insert(name.as_str(), path.clone()) {
+                    problem(format!("{}.name", path), &format!("{} is already the name of {}", name, first));
+                }
+            }
+
+            match webhook.destination {
+                WebhookType::Discord => {
+                    let urls = webhook.urls.as_deref().unwrap_or_default();
+                    if urls.is_empty() {
+                        problem(format!("{}.urls", path), "a discord webhook needs urls or urls_file");
+                    }
+                    for (k, url) in urls.iter().enumerate() {
+                        let path = format!("{}.urls[{}]", path, k);
+                        match services.check_url(url) {
+                            Ok(()) if !is_discord_webhook(url) => {
+                                problem(path, "must look like https://discord.com/api/webhooks/<id>/<token>");
+                            }
+                            Ok(()) => {}
+                            Err(e) => problem(path, &e.to_string()),
+                        }
+                    }
+                    if webhook.credentials.is_some() {
+                        problem(format!("{}.credentials", path), "only applies to bluesky webhooks");
+                    }
+                    if webhook.bot_token.as_deref() == Some("") {
+                        problem(format!("{}.bot_token", path), "must not be empty");
+                    }
+                }
+                WebhookType::BlueSky => {
+                    match &webhook.credentials {
+                        Some(credentials) if !is_handle(&credentials.username) => {
+                            problem(
+                                format!("{}.credentials.username", path),
+                                "must be a Bluesky handle such as name.bsky.social, or a did"
+                            );
+                        }
+                        Some(_) => {}
+                        None => problem(format!("{}.credentials", path), "a bluesky webhook needs credentials"),
+                    }
+                    let discord_only = [
+                        ("urls", webhook.urls.is_some()),
+                        ("urls_file", webhook.urls_file.is_some()),
+                        ("groups", webhook.groups.is_some()),
+                        ("is_forum", webhook.is_forum.is_some()),
+                        ("bot_token", webhook.bot_token.is_some()),
+                    ];
+                    for (field, set) in discord_only {
+                        if set {
+                            problem(format!("{}.{}", path, field), "only applies to discord webhooks");
+                        }
+                    }
+                }
+            }
+
+            if let Some(retry) = &webhook.retry {
+                let path = format!("{}.retry", path);
+                if retry.max_attempts == 0 {
+                    problem(format!("{}.max_attempts", path), "must be at least 1");
+                }
+                if retry.base_delay > retry.max_delay {
+                    problem(format!("{}.base_delay", path), "must not be more than max_delay");
+                }
+                if !(0.0..=1.0).contains(&retry.jitter) {
+                    problem(format!("{}.jitter", path), "must be between 0.0 and 1.0");
+                }
+            }
+        }
+    }
+
+    if let Some(storage) = &config.storage {
+        if storage.backend == StorageBackend::Postgres && storage.dsn.is_none() {
+            problem("storage.dsn".to_string(), "the postgres backend needs a connection string");
+        }
+    }
+
+    if let Some(daemon) = &config.daemon {
+        if daemon.poll_interval == 0 {
+            problem("daemon.poll_interval".to_string(), "must be at least 1 second");
+        }
+        if !(0.0..=1.0).contains(&daemon.jitter) {
+            problem("daemon.jitter".to_string(), "must be between 0.0 and 1.0");
+        }
+    }
+
+    if let Some(fetch) = &config.fetch {
+        if fetch.concurrency == 0 {
+            problem("fetch.concurrency".to_string(), "must be at least 1");
+        }
+        if fetch.timeout == 0 {
+            problem("fetch.timeout".to_string(), "must be at least 1 second");
+        }
+        if !(fetch.requests_per_second > 0.0 && fetch.requests_per_second.is_finite()) {
+            problem("fetch.requests_per_second".to_string(), "must be more than 0");
+        }
+    }
+
+    if let Some(websub) = &config.websub {
+        if !is_listen_address(&websub.listen) {
+            problem("websub.listen".to_string(), "must be a host:port to listen on");
+        }
+        match Url::parse(&websub.callback_url) {
+            Ok(url) if url.scheme() == "https" || url.scheme() == "http" => {}
+            _ => problem("websub.callback_url".to_string(), "must be the public http(s) URL of the callback server"),
+        }
+        if websub.secret.is_empty() {
+            problem("websub.secret".to_string(), "is required, the hub signs every push with it");
+        }
+        if websub.lease_seconds == 0 {
+            problem("websub.lease_seconds".to_string(), "must be at least 1 second");
+        }
+    }
+
+    if let Some(metrics) = &config.metrics {
+        if !is_listen_address(&metrics.listen) {
+            problem("metrics.listen".to_string(), "must be a host:port to listen on");
+        }
+    }
+
+    if let Some(admin) = &config.admin {
+        if !is_listen_address(&admin.listen) {
+            problem("admin.listen".to_string(), "must be a host:port to listen on");
+        }
+        if admin.token.is_empty() {
+            problem("admin.token".to_string(), "must not be empty");
+        }
+    }
+
+    problems
+}
+
+/// `<anything>/webhooks/<id>/<token>`, so stand-ins work as long as they keep Discord's path
+fn is_discord_webhook(url: &str) -> bool {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => {
+            return false;
+        }
+    };
+    let segments: Vec<&str> = url.path_segments().map_or(vec![], |segments| segments.collect());
+    match segments.iter().position(|segment| *segment == "webhooks") {
+        Some(i) =>
+            match &segments[i + 1..] {
+                [id, token] => !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()) && !token.is_empty(),
+                _ => false,
+            }
+        None => false,
+    }
+}
+
+/// A domain name handle such as `name.bsky.social`, or a `did:` identifier
+fn is_handle(handle: &str) -> bool {
+    if let Some(did) = handle.strip_prefix("did:") {
+        return did.split_once(':').is_some_and(|(method, id)| !method.is_empty() && !id.is_empty());
+    }
+
+    let labels: Vec<&str> = handle.split('.').collect();
+    let is_label = |label: &&str| {
+        !label.is_empty() &&
+            label.len() <= 63 &&
+            !label.starts_with('-') &&
+            !label.ends_with('-') &&
+            label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+    };
+
+    handle.len() <= 253 &&
+        labels.len() >= 2 &&
+        labels.iter().all(is_label) &&
+        labels.last().is_some_and(|tld| !tld.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+/// `host:port`, as the servers bind it
+fn is_listen_address(listen: &str) -> bool {
+    match listen.rsplit_once(':') {
+        Some((host, port)) => !host.is_empty() && port.parse::<u16>().is_ok(),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Webhook;
+
+    /// The first fenced block of `language` in the README
+    fn example(language: &str) -> &'static str {
+        let readme = include_str!("../README.md");
+        let fence = format!("```{}\n", language);
+        let start = readme.find(&fence).unwrap() + fence.len();
+        let end = start + readme[start..].find("```").unwrap();
+        &readme[start..end]
+    }
+
+    #[test]
+    fn parses_the_readme_examples() {
+        for format in [ConfigFormat::Yaml, ConfigFormat::Toml, ConfigFormat::Hcl] {
+            let language = format!("{:?}", format).to_lowercase();
+            let config = parse(example(&language), format).unwrap_or_else(|e| panic!("{}{}", language, e));
+            assert_eq!(config.author.name, "Author Name", "{}", language);
+            assert_eq!(config.playlist.len(), 1, "{}", language);
+            assert!(!config.playlist[0].webhooks.is_empty(), "{}", language);
+        }
+    }
+
+    /// The TOML example, with a webhook URL in place of its placeholder
+    fn valid() -> Config {
+        let mut config = parse(example("toml"), ConfigFormat::Toml).unwrap();
+        config.playlist[0].webhooks[0].urls = Some(vec!["https://discord.com/api/webhooks/123/token".to_string()]);
+        config
+    }
+
+    fn paths(config: &Config) -> Vec<String> {
+        validate(config).into_iter().map(|problem| problem.path).collect()
+    }
+
+    #[test]
+    fn accepts_a_valid_configuration() {
+        assert_eq!(validate(&valid()), vec![]);
+    }
+
+    #[test]
+    fn reports_every_problem() {
+        let mut config = valid();
+        config.log_level = Some("loud".to_string());
+        config.playlist.push(config.playlist[0].clone());
+        config.playlist[1].webhooks[0].urls = Some(vec!["https://discord.com/api/v10/channels/1".to_string()]);
+
+        assert_eq!(paths(&config), vec!["log_level", "playlist[1].id", "playlist[1].webhooks[0].urls[0]"]);
+    }
+
+    #[test]
+    fn checks_what_each_destination_needs() {
+        let mut config = valid();
+        let webhook = Webhook {
+            destination: WebhookType::BlueSky,
+            urls: None,
+            groups: Some(vec![]),
+            ..config.playlist[0].webhooks[0].clone()
+        };
+        config.playlist[0].webhooks[0].urls = None;
+        config.playlist[0].webhooks.push(webhook);
+
+        assert_eq!(
+            paths(&config),
+            vec!["playlist[0].webhooks[0].urls", "playlist[0].webhooks[1].credentials", "playlist[0].webhooks[1].groups"]
+        );
+    }
+
+    #[test]
+    fn finds_duplicate_webhook_names() {
+        let mut config = valid();
+        config.playlist[0].webhooks[0].name = Some("announcements".to_string());
+        let webhook = config.playlist[0].webhooks[0].clone();
+        config.playlist[0].webhooks.push(webhook);
+
+        let problems = validate(&config);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].path, "playlist[0].webhooks[1].name");
+        assert_eq!(problems[0].message, "announcements is already the name of playlist[0].webhooks[0]");
+    }
+
+    #[test]
+    fn never_quotes_webhook_urls() {
+        let mut config = valid();
+        config.playlist[0].webhooks[0].urls = Some(vec!["http://discord.com/api/webhooks/1/s3cr3t".to_string()]);
+
+        let problems = validate(&config);
+        assert_eq!(problems.len(), 1);
+        assert!(!problems[0].message.contains("s3cr3t"), "{}", problems[0].message);
+    }
+
+    #[test]
+    fn recognizes_handles() {
+        assert!(is_handle("alaydriem.com"));
+        assert!(is_handle("name.bsky.social"));
+        assert!(is_handle("did:plc:abc123"));
+        assert!(!is_handle("alaydriem"));
+        assert!(!is_handle("@name.bsky.social"));
+        assert!(!is_handle("-name.bsky.social"));
+        assert!(!is_handle("name.123"));
+        assert!(!is_handle("did:plc"));
+        assert!(!is_handle("did::abc"));
+    }
+
+    #[test]
+    fn recognizes_discord_webhooks() {
+        assert!(is_discord_webhook("https://discord.com/api/webhooks/123/token"));
+        assert!(is_discord_webhook("http://127.0.0.1:8080/stand-in/webhooks/123/token"));
+        assert!(!is_discord_webhook("https://discord.com/api/webhooks/123"));
+        assert!(!is_discord_webhook("https://discord.com/api/webhooks/abc/token"));
+        assert!(!is_discord_webhook("https://discord.com/api/webhooks/123/token/extra"));
+        assert!(!is_discord_webhook("not a url"));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let misspelled = example("toml").replace("destination = ", "destinaton = ");
+        let error = parse(&misspelled, ConfigFormat::Toml).err().unwrap();
+        assert!(error.contains("destinaton"), "{}", error);
+
+        let misspelled = example("yaml").replace("  lease_seconds:", "  lease:");
+        let error = parse(&misspelled, ConfigFormat::Yaml).err().unwrap();
+        assert!(error.contains("lease"), "{}", error);
+    }
+
+    #[test]
+    fn schema_requires_what_each_destination_needs() {
+        let schema = serde_json::to_value(schema()).unwrap();
+        let webhook = &schema["definitions"]["Webhook"];
+        assert_eq!(webhook["additionalProperties"], false);
+        assert_eq!(webhook["allOf"].as_array().unwrap().len(), 2);
+        assert_eq!(schema["definitions"]["Credentials"]["oneOf"].as_array().unwrap().len(), 2);
+        assert_eq!(schema["additionalProperties"], false);
+    }
+
+    #[test]
+    fn reports_hcl_errors_as_hcl() {
+        let error = parse("log_level: info\n", ConfigFormat::Hcl).err().unwrap();
+        assert!(error.starts_with(": "), "{}", error);
+    }
+}
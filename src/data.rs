@@ -15,4 +15,11 @@ pub struct Entry {
     pub id: String,
     pub published: String,
     pub updated: String,
+    pub author: Option<Author>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Author {
+    pub name: String,
+    pub uri: Option<String>,
 }
@@ -0,0 +1,71 @@
+use serde::{ Deserialize, Serialize };
+
+use crate::WebhookType;
+
+/// The state of a single (video, playlist, destination, target) delivery
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DeliveryStatus {
+    #[serde(rename = "pending")]
+    Pending,
+    #[serde(rename = "delivered")]
+    Delivered,
+    #[serde(rename = "dead")]
+    Dead,
+    #[serde(rename = "skipped")]
+    Skipped,
+    /// Delivered, then taken down again because the video was removed
+    #[serde(rename = "retracted")]
+    Retracted,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Dead => "dead",
+            DeliveryStatus::Skipped => "skipped",
+            DeliveryStatus::Retracted => "retracted",
+        }
+    }
+}
+
+/// Identifies a single sink for a video.
+/// `target` is the Discord webhook URL or the Bluesky account
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeliveryKey<'a> {
+    pub video: &'a str,
+    pub playlist: &'a str,
+    pub destination: &'a WebhookType,
+    pub target: &'a str,
+}
+
+/// A row of the delivery ledger
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Delivery {
+    pub video: String,
+    pub playlist: String,
+    pub destination: String,
+    pub target: String,
+    pub status: String,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: Option<String>,
+    pub updated_at: String,
+    pub message_id: Option<String>,
+    pub thread_id: Option<String>,
+    pub record_cid: Option<String>,
+    /// The video changed after this delivery went out, and the announcement needs an edit
+    pub edit_pending: bool,
+}
+
+/// What a destination hands back for a delivery, needed to edit the announcement later
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Receipt {
+    /// The Discord message id, or the at:// URI of the Bluesky post
+    pub message_id: Option<String>,
+    /// The Discord forum thread the message started
+    pub thread_id: Option<String>,
+    /// The CID of the Bluesky post, needed to reply to it
+    pub record_cid: Option<String>,
+}
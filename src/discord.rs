@@ -0,0 +1,369 @@
+use anyhow::anyhow;
+use reqwest::header::HeaderMap;
+use reqwest::{ RequestBuilder, Response, StatusCode, Url };
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use webhook::models::{ AllowedMention, Message };
+
+use crate::delivery::Receipt;
+use crate::metrics::metrics;
+use crate::store::Video;
+use crate::{ Config, Webhook };
+
+/// How many times a single request is re-sent after Discord answers with a 429
+const MAX_RATE_LIMITED_RETRIES: u32 = 5;
+
+/// Discord rejects thread names longer than this
+const MAX_THREAD_NAME_LENGTH: usize = 100;
+
+/// The parts of the message object Discord returns for `?wait=true`
+#[derive(Debug, Deserialize)]
+struct SentMessage {
+    id: String,
+    channel_id: String,
+}
+
+#[derive(Debug, Clone)]
+struct Bucket {
+    remaining: u32,
+    reset: Instant,
+}
+
+#[derive(Debug, Default)]
+struct RateLimits {
+    /// The bucket Discord reported for each webhook URL
+    webhooks: HashMap<String, String>,
+    /// Bucket id, or the webhook URL until Discord tells us its bucket
+    buckets: HashMap<String, Bucket>,
+    global_reset: Option<Instant>,
+}
+
+impl RateLimits {
+    fn bucket_key(&self, url: &str) -> String {
+        match self.webhooks.get(url) {
+            Some(bucket) => bucket.clone(),
+            None => url.to_string(),
+        }
+    }
+
+    /// How long we must wait before a request to this webhook is allowed
+    fn delay(&self, url: &str) -> Option<Duration> {
+        let now = Instant::now();
+        let mut until = self.global_reset.filter(|reset| *reset > now);
+
+        if let Some(bucket) = self.buckets.get(&self.bucket_key(url)) {
+            if bucket.remaining == 0 && bucket.reset > now {
+                until = Some(until.map_or(bucket.reset, |global| global.max(bucket.reset)));
+            }
+        }
+
+        until.map(|until| until - now)
+    }
+
+    fn update(&mut self, url: &str, headers: &HeaderMap) {
+        if let Some(bucket) = header::<String>(headers, "x-ratelimit-bucket") {
+            self.webhooks.insert(url.to_string(), bucket);
+        }
+
+        let remaining = header::<u32>(headers, "x-ratelimit-remaining");
+        let reset_after = header::<f64>(headers, "x-ratelimit-reset-after");
+        if let (Some(remaining), Some(reset_after)) = (remaining, reset_after) {
+            let key = self.bucket_key(url);
+            self.buckets.insert(key, Bucket {
+                remaining,
+                reset: Instant::now() + Duration::from_secs_f64(reset_after.max(0.0)),
+            });
+        }
+    }
+
+    fn limited(&mut self, url: &str, retry_after: Duration, global: bool) {
+        let reset = Instant::now() + retry_after;
+        if global {
+            self.global_reset = Some(reset);
+        } else {
+            let key = self.bucket_key(url);
+            self.buckets.insert(key, Bucket { remaining: 0, reset });
+        }
+    }
+}
+
+/// Sends Discord webhook requests, pacing them by the rate limits Discord reports
+/// for each bucket and webhook, and the global limit
+pub struct DiscordSender {
+    client: reqwest::Client,
+    /// The API base URL, for the requests that need a bot token
+    api: String,
+    limits: Mutex<RateLimits>,
+}
+
+impl DiscordSender {
+    pub fn new(client: reqwest::Client, api: String) -> Self {
+        Self {
+            client,
+            api: api.trim_end_matches('/').to_string(),
+            limits: Mutex::new(RateLimits::default()),
+        }
+    }
+
+    /// Builds the announcement message for a video
+    pub fn render(config: &Config, webhook: &Webhook, v: &Video) -> Message {
+        let groups = webhook.groups.clone().unwrap_or_default();
+        let services = config.services();
+        let url = services.watch(&v.id);
+
+        let mut message = Message::new();
+        message
+            .username(&config.bot.name)
+            .avatar_url(&config.bot.icon)
+            .content(&format!("{} :: {}", groups.join(" ").as_str(), &url))
+            .thread_name(&v.title, webhook.is_forum.unwrap_or(false))
+            .allow_mentions(
+                Some(vec![AllowedMention::UserMention, AllowedMention::RoleMention]),
+                None,
+                None,
+                false
+            )
+            .embed(|embed|
+                embed
+                    .description(&format!("### [{}]({})", &v.title, &url))
+                    .color("16711680")
+                    .image(&services.thumbnail(&v.id))
+                    .video(&url)
+                    .thumbnail("https://www.iconfinder.com/icons/317714/download/png/256")
+                    .author(
+                        &config.author.name,
+                        Some(config.author.clone().url),
+                        Some(config.author.clone().icon)
+                    )
+            );
+
+        message
+    }
+
+    /// Replaces an announcement once its video is gone. The empty embed list drops the old preview
+    pub fn render_removed(config: &Config, v: &Video) -> Message {
+        let mut message = Message::new();
+        message
+            .username(&config.bot.name)
+            .avatar_url(&config.bot.icon)
+            .content(&format!("~~{}~~ is no longer available.", &v.title));
+
+        message
+    }
+
+    /// Posts a message to the webhook and waits for Discord to return it, so it can be edited later.
+    /// For forums the message's channel is the thread it started
+    pub async fn send(&self, url: &str, message: &Message, is_forum: bool) -> anyhow::Result<Receipt> {
+        let response = self.execute(url, || {
+            self.client.post(url).query(&[("wait", "true")]).json(message)
+        }).await?;
+        // Discord accepted the message, so an answer it can't be read from still counts as
+        // delivered. Sending it again would post a duplicate; it just can't be edited later
+        let sent: SentMessage = match response.json().await {
+            Ok(sent) => sent,
+            Err(e) => {
+                tracing::warn!("Unable to read the message Discord sent: {:?}", e.without_url());
+                return Ok(Receipt::default());
+            }
+        };
+
+        Ok(Receipt {
+            message_id: Some(sent.id),
+            thread_id: if is_forum {
+                Some(sent.channel_id)
+            } else {
+                None
+            },
+            record_cid: None,
+        })
+    }
+
+    /// Replaces the content and embeds of a message the webhook sent earlier
+    pub async fn edit(
+        &self,
+        url: &str,
+        message_id: &str,
+        thread_id: Option<&str>,
+        message: &Message
+    ) -> anyhow::Result<()> {
+        let endpoint = message_endpoint(url, message_id, thread_id)?;
+
+        // Only these fields can change on an existing message
+        let rendered = serde_json::to_value(message)?;
+        let mut body = serde_json::Map::new();
+        for field in ["content", "embeds", "allowed_mentions"] {
+            if let Some(value) = rendered.get(field) {
+                body.insert(field.to_string(), value.clone());
+            }
+        }
+
+        self.execute(url, || self.client.patch(endpoint.clone()).json(&body)).await?;
+        Ok(())
+    }
+
+    /// Deletes a message the webhook sent earlier
+    pub async fn delete(&self, url: &str, message_id: &str, thread_id: Option<&str>) -> anyhow::Result<()> {
+        let endpoint = message_endpoint(url, message_id, thread_id)?;
+        self.execute(url, || self.client.delete(endpoint.clone())).await?;
+        Ok(())
+    }
+
+    /// Deletes a whole forum thread, which needs a bot token with Manage Threads in the forum
+    pub async fn delete_thread(&self, bot_token: &str, thread_id: &str) -> anyhow::Result<()> {
+        let endpoint = format!("{}/channels/{}", self.api, thread_id);
+        self.execute(&endpoint, || {
+            self.client.delete(&endpoint).header("Authorization", format!("Bot {}", bot_token))
+        }).await?;
+        Ok(())
+    }
+
+    /// Renames a forum thread. Webhooks can't do this, so it needs a bot token
+    /// with Manage Threads in the forum
+    pub async fn rename_thread(&self, bot_token: &str, thread_id: &str, name: &str) -> anyhow::Result<()> {
+        let endpoint = format!("{}/channels/{}", self.api, thread_id);
+        let name: String = name.chars().take(MAX_THREAD_NAME_LENGTH).collect();
+
+        self.execute(&endpoint, || {
+            self.client
+                .patch(&endpoint)
+                .header("Authorization", format!("Bot {}", bot_token))
+                .json(&serde_json::json!({ "name": name }))
+        }).await?;
+        Ok(())
+    }
+
+    /// Runs a request against a webhook, waiting out any known rate limit first
+    /// and re-sending it as long as Discord answers with 429. Limits are tracked under `url`,
+    /// the webhook itself, so edits and deletes of its messages share pacing with its sends
+    async fn execute<F>(&self, url: &str, request: F) -> anyhow::Result<Response>
+        where F: Fn() -> RequestBuilder
+    {
+        let mut rate_limited = 0;
+        loop {
+            let delay = self.limits.lock().unwrap().delay(url);
+            if let Some(delay) = delay {
+                tracing::debug!("Waiting {:?} for the Discord rate limit", delay);
+                metrics().rate_limit_waited(delay);
+                tokio::time::sleep(delay).await;
+            }
+
+            let response = request().send().await.map_err(|e| e.without_url())?;
+            self.limits.lock().unwrap().update(url, response.headers());
+
+            if response.status() != StatusCode::TOO_MANY_REQUESTS {
+                if response.status().is_success() {
+                    return Ok(response);
+                }
+
+                let status = response.status();
+                let body = response.text().await.unwrap_or_default();
+                return Err(anyhow!("Discord responded with {}: {}", status, body));
+            }
+
+            rate_limited += 1;
+            if rate_limited > MAX_RATE_LIMITED_RETRIES {
+                return Err(anyhow!("Discord kept rate limiting the webhook"));
+            }
+
+            let global =
+                header::<bool>(response.headers(), "x-ratelimit-global").unwrap_or(false) ||
+                header::<String>(response.headers(), "x-ratelimit-scope").as_deref() == Some("global");
+            let header_retry_after = header::<f64>(response.headers(), "retry-after");
+            let retry_after = match header_retry_after {
+                Some(seconds) => seconds,
+                None =>
+                    match response.json::<serde_json::Value>().await {
+                        Ok(body) => body["retry_after"].as_f64().unwrap_or(1.0),
+                        Err(_) => 1.0,
+                    }
+            };
+
+            tracing::warn!("Discord rate limited the webhook, retrying in {}s", retry_after);
+            self.limits
+                .lock()
+                .unwrap()
+                .limited(url, Duration::from_secs_f64(retry_after.max(0.0)), global);
+        }
+    }
+}
+
+/// The `/messages/{id}` endpoint of a webhook, scoped to a forum thread when there is one
+fn message_endpoint(url: &str, message_id: &str, thread_id: Option<&str>) -> anyhow::Result<Url> {
+    let mut endpoint = Url::parse(url)?;
+    match endpoint.path_segments_mut() {
+        Ok(mut segments) => {
+            segments.push("messages").push(message_id);
+        }
+        Err(_) => {
+            return Err(anyhow!("The webhook URL can't be extended with a message id"));
+        }
+    }
+    if let Some(thread_id) = thread_id {
+        endpoint.query_pairs_mut().append_pair("thread_id", thread_id);
+    }
+
+    Ok(endpoint)
+}
+
+fn header<T: FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WEBHOOK: &str = "https://discord.com/api/webhooks/1/token";
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn waits_once_a_bucket_is_exhausted() {
+        let mut limits = RateLimits::default();
+        assert_eq!(limits.delay(WEBHOOK), None);
+
+        limits.update(WEBHOOK, &headers(&[
+            ("x-ratelimit-bucket", "abc"),
+            ("x-ratelimit-remaining", "1"),
+            ("x-ratelimit-reset-after", "2.5"),
+        ]));
+        assert_eq!(limits.delay(WEBHOOK), None);
+
+        limits.update(WEBHOOK, &headers(&[
+            ("x-ratelimit-remaining", "0"),
+            ("x-ratelimit-reset-after", "2.5"),
+        ]));
+        let delay = limits.delay(WEBHOOK).unwrap();
+        assert!(delay > Duration::from_secs(2) && delay <= Duration::from_millis(2500), "{:?}", delay);
+        assert_eq!(limits.delay("https://discord.com/api/webhooks/2/token"), None);
+    }
+
+    #[test]
+    fn webhooks_sharing_a_bucket_wait_together() {
+        let mut limits = RateLimits::default();
+        let other = "https://discord.com/api/webhooks/2/token";
+        limits.update(WEBHOOK, &headers(&[("x-ratelimit-bucket", "abc")]));
+        limits.update(other, &headers(&[("x-ratelimit-bucket", "abc")]));
+
+        limits.limited(WEBHOOK, Duration::from_secs(10), false);
+        assert!(limits.delay(other).is_some());
+    }
+
+    #[test]
+    fn a_global_limit_holds_every_webhook() {
+        let mut limits = RateLimits::default();
+        limits.limited(WEBHOOK, Duration::from_secs(10), true);
+
+        let delay = limits.delay("https://discord.com/api/webhooks/2/token").unwrap();
+        assert!(delay > Duration::from_secs(9), "{:?}", delay);
+    }
+}
@@ -0,0 +1,94 @@
+use anyhow::anyhow;
+use fs2::FileExt;
+use std::fs::{ self, File, OpenOptions };
+use std::io::{ Seek, Write };
+use std::path::Path;
+use tokio_postgres::NoTls;
+
+use crate::shutdown;
+use crate::store::{ StorageBackend, StorageConfig };
+
+/// The postgres advisory lock key relays sharing a database take, "relay" in ASCII
+const ADVISORY_LOCK: i64 = 0x72_65_6c_61_79;
+
+/// Keeps a second relay from sending while this one is. The operating system holds an exclusive
+/// lock on the lock file for as long as the process runs, and lets go of it however the process ends,
+/// so there is never a stale lock to take over. The PID in the file is only there for people.
+/// With the postgres backend the database holds a lock too, since relays on other hosts may share it
+pub struct InstanceLock {
+    _file: File,
+    _database: Option<tokio_postgres::Client>,
+}
+
+impl InstanceLock {
+    /// Takes the lock, or fails when another process holds it
+    pub async fn acquire(path: &Path, storage: &StorageConfig) -> anyhow::Result<Self> {
+        // Never truncated before the lock is ours, so the holder's PID stays readable
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .map_err(|e| anyhow!("Unable to open {}: {}", path.display(), e))?;
+
+        if file.try_lock_exclusive().is_err() {
+            let holder = fs::read_to_string(path).unwrap_or_default();
+            return match holder.trim() {
+                "" => Err(anyhow!("Another instance holds {}", path.display())),
+                pid => Err(anyhow!("Another instance (pid {}) holds {}", pid, path.display())),
+            };
+        }
+
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+
+        let database = match (&storage.backend, &storage.dsn) {
+            (StorageBackend::Postgres, Some(dsn)) => Some(lock_database(dsn).await?),
+            _ => None,
+        };
+
+        Ok(Self { _file: file, _database: database })
+    }
+}
+
+/// Takes a session-level advisory lock on a connection of its own. The lock goes with the
+/// connection, so losing it asks the process to shut down before another relay takes over
+async fn lock_database(dsn: &str) -> anyhow::Result<tokio_postgres::Client> {
+    let (client, connection) = tokio_postgres::connect(dsn, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::error!("Lost the connection holding the instance lock, shutting down: {:?}", e);
+            shutdown::request();
+        }
+    });
+
+    let locked: bool = client
+        .query_one("SELECT pg_try_advisory_lock($1)", &[&ADVISORY_LOCK]).await?
+        .try_get(0)?;
+    if !locked {
+        return Err(anyhow!("Another instance holds the lock in the database"));
+    }
+
+    Ok(client)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn only_one_holder_at_a_time() {
+        let path = std::env::temp_dir().join(format!("relay-{}.lock", std::process::id()));
+        let storage = StorageConfig::default();
+
+        let lock = InstanceLock::acquire(&path, &storage).await.unwrap();
+        let error = InstanceLock::acquire(&path, &storage).await.err().unwrap();
+        assert!(error.to_string().contains(&std::process::id().to_string()), "{}", error);
+
+        drop(lock);
+        InstanceLock::acquire(&path, &storage).await.unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,84 +1,165 @@
 use anyhow::anyhow;
-use atrium_api::app::bsky::embed::external::External;
-use atrium_api::app::bsky::embed::external::ExternalData;
-use atrium_api::app::bsky::embed::external::Main;
-use atrium_api::app::bsky::embed::external::MainData;
-use atrium_api::app::bsky::feed::post::RecordEmbedRefs;
-use atrium_api::types::string::Language;
-use atrium_api::types::Blob;
-use atrium_api::types::BlobRef;
-use atrium_api::types::CidLink;
-use atrium_api::types::TypedBlobRef;
-use atrium_api::types::UnTypedBlobRef;
-use atrium_api::types::Union;
+use schemars::JsonSchema;
 use serde::{ Deserialize, Serialize };
-use webhook::client::WebhookClient;
-use webhook::models::AllowedMention;
-use std::fs;
-use std::path::Path;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::str::FromStr;
-use std::time::Duration;
 use tracing::Level;
 use tracing_appender::non_blocking::NonBlocking;
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::fmt::SubscriberBuilder;
-use atrium_api::types::string::Datetime as BskyDateTime;
-use atrium_api::app::bsky::feed::post::RecordData;
-use bsky_sdk::BskyAgent;
-use rusqlite::Connection;
 
 extern crate tokio;
+mod admin;
+mod bluesky;
+mod cli;
+mod commands;
+mod config;
 mod data;
+mod delivery;
+mod discord;
+mod lock;
+mod metrics;
+mod reload;
+mod retry;
+mod scheduler;
+mod secrets;
+mod services;
+mod shutdown;
+mod store;
+mod websub;
+mod youtube;
+use crate::admin::AdminConfig;
+use crate::bluesky::BlueskySender;
+use crate::cli::{ Cli, Command, DeadLetterCommand };
 use crate::data::Feed;
+use crate::delivery::{ DeliveryKey, DeliveryStatus };
+use crate::discord::DiscordSender;
+use crate::lock::InstanceLock;
+use crate::metrics::{ metrics, MetricsConfig };
+use crate::reload::Source;
+use crate::retry::RetryPolicy;
+use crate::scheduler::{ DaemonConfig, Schedule };
+use crate::secrets::{ redact, Secrets };
+use crate::services::ServicesConfig;
+use crate::store::{ Pause, PauseScope, StorageConfig, Upsert, Video, VideoStore };
+use crate::websub::WebSubConfig;
+use crate::youtube::{ FetchConfig, Fetched, YouTube, FEED_SIZE };
+use clap::Parser;
+use tokio::sync::{ mpsc, watch };
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
-pub struct Video {
-    pub id: String,
-    pub playlist: String,
-    pub title: String,
-    pub author: String,
-    pub timestamp: String,
-    pub hooked: i32,
-}
-
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    #[serde(deserialize_with = "config::one_or_many")]
+    #[schemars(with = "Vec<Playlist>")]
     pub playlist: Vec<Playlist>,
     pub log_level: Option<String>,
     pub author: User,
     pub bot: User,
+    pub storage: Option<StorageConfig>,
+    /// When a video is in several playlists that share a destination, only announce it there once
+    pub dedupe: Option<bool>,
+    pub daemon: Option<DaemonConfig>,
+    /// Subscribe to YouTube's WebSub hub in daemon mode, so new videos are pushed instead of polled
+    pub websub: Option<WebSubConfig>,
+    /// Serve /healthz, /readyz and /metrics in daemon mode
+    pub metrics: Option<MetricsConfig>,
+    /// Held while sending, so overlapping runs can't announce the same video twice. Defaults to relay.lock
+    pub lock_file: Option<String>,
+    /// How many feeds are fetched at once, request timeouts, and the per-host rate limit
+    pub fetch: Option<FetchConfig>,
+    /// Base URLs of YouTube, Bluesky, Discord and the WebSub hub, for pointing the relay at stand-ins
+    pub services: Option<ServicesConfig>,
+    /// Serve the admin API in daemon mode
+    pub admin: Option<AdminConfig>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct User {
     pub name: String,
     pub url: String,
     pub icon: String,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Playlist {
     pub id: String,
     pub name: String,
+    #[serde(deserialize_with = "config::one_or_many")]
+    #[schemars(with = "Vec<Webhook>")]
     pub webhooks: Vec<Webhook>,
+    /// When false, the first run only records the videos already in the playlist
+    /// instead of announcing them
+    pub backfill: Option<bool>,
+    /// Seconds between two polls in daemon mode, overriding `daemon.poll_interval`
+    pub poll_interval: Option<u64>,
+    /// Subscribe WebSub to this channel's uploads instead of the playlist
+    pub channel_id: Option<String>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Webhook {
+    /// Lets `test-destination` find this webhook
+    pub name: Option<String>,
     pub destination: WebhookType,
     pub is_forum: Option<bool>,
+    /// Discord webhook URLs. Each one may be, or contain, a `${ENV_VAR}` or `${file:/path}` reference
     pub urls: Option<Vec<String>>,
+    /// A file with more webhook URLs, one per line, such as a mounted secret
+    pub urls_file: Option<String>,
     pub groups: Option<Vec<String>>,
     pub credentials: Option<Credentials>,
+    pub retry: Option<RetryPolicy>,
+    /// Lets the relay rename forum threads when a video is retitled, which webhooks can't do
+    pub bot_token: Option<String>,
+    /// What happens to announcements of a video that is later removed or made private
+    pub on_removed: Option<RemovedAction>,
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
 pub struct Credentials {
     pub username: String,
+    /// May be, or contain, a `${ENV_VAR}` or `${file:/path}` reference
+    #[serde(default)]
     pub password: String,
+    /// Read the password from this file instead, such as a mounted secret
+    pub password_file: Option<String>,
+}
+
+impl std::fmt::Debug for Webhook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Webhook")
+            .field("name", &self.name)
+            .field("destination", &self.destination)
+            .field("is_forum", &self.is_forum)
+            .field("urls", &self.urls.as_ref().map(|urls| urls.iter().map(|url| redact(url)).collect::<Vec<_>>()))
+            .field("urls_file", &self.urls_file)
+            .field("groups", &self.groups)
+            .field("credentials", &self.credentials)
+            .field("retry", &self.retry)
+            .field("bot_token", &self.bot_token.as_ref().map(|_| "***"))
+            .field("on_removed", &self.on_removed)
+            .finish()
+    }
+}
+
+impl std::fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &"***")
+            .field("password_file", &self.password_file)
+            .finish()
+    }
 }
 
-#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
 pub enum WebhookType {
     #[serde(rename = "discord")]
     Discord,
@@ -86,33 +167,112 @@ pub enum WebhookType {
     BlueSky,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    let config: Config = match fs::read_to_string("data.hcl") {
-        Ok(data) =>
-            match serde_yaml::from_str(data.as_str()) {
-                Ok(config) => config,
-                Err(e) => {
-                    println!("Error: {:?}", e);
-                    return Err(anyhow!(e.to_string()));
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum RemovedAction {
+    /// Delete the announcement, or its whole forum thread when there is a bot_token
+    #[serde(rename = "delete")]
+    Delete,
+    /// Discord messages are edited to say the video is gone; Bluesky posts get a reply saying so
+    #[serde(rename = "edit")]
+    Edit,
+    #[serde(rename = "ignore")]
+    Ignore,
+}
+
+impl Config {
+    pub fn lock_file(&self) -> PathBuf {
+        PathBuf::from(self.lock_file.as_deref().unwrap_or("relay.lock"))
+    }
+
+    pub fn services(&self) -> ServicesConfig {
+        self.services.clone().unwrap_or_default()
+    }
+}
+
+impl WebhookType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WebhookType::Discord => "discord",
+            WebhookType::BlueSky => "bluesky",
+        }
+    }
+}
+
+impl Webhook {
+    /// The individual sinks of this webhook: each Discord URL, or the Bluesky account
+    pub fn targets(&self) -> Vec<String> {
+        match self.destination {
+            WebhookType::Discord => self.urls.clone().unwrap_or_default(),
+            WebhookType::BlueSky =>
+                match &self.credentials {
+                    Some(credentials) => vec![credentials.username.clone()],
+                    None => vec![],
                 }
-            }
-        Err(e) => {
-            println!("Error: {}", e.to_string());
-            return Err(anyhow!(e.to_string()));
         }
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        self.retry.clone().unwrap_or_default()
+    }
+
+    pub fn removed_action(&self) -> RemovedAction {
+        self.on_removed.unwrap_or(RemovedAction::Ignore)
+    }
+
+    /// Whether the webhook was paused through the admin API, by its name
+    pub fn is_paused(&self, pauses: &[Pause]) -> bool {
+        self.name
+            .as_ref()
+            .is_some_and(|name| pauses.iter().any(|p| p.is(PauseScope::Destination, name)))
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+
+    // The schema describes configuration files, so it doesn't need one
+    if let Some(Command::Schema) = cli.command {
+        println!("{}", serde_json::to_string_pretty(&config::schema())?);
+        return Ok(());
+    }
+
+    let source = Source {
+        path: cli.config.clone(),
+        format: cli.config_format,
+        db: cli.db.clone(),
+        secrets: Secrets::default(),
     };
+    let config = source.load()?;
+
+    if let Some(Command::Validate) = cli.command {
+        println!(
+            "{} is valid: {} playlists, {} webhooks",
+            cli.config.display(),
+            config.playlist.len(),
+            config.playlist
+                .iter()
+                .map(|p| p.webhooks.len())
+                .sum::<usize>()
+        );
+        return Ok(());
+    }
 
-    let log_level = match config.log_level {
-        Some(level) => level,
+    let log_level = match &config.log_level {
+        Some(level) => level.clone(),
         None => "INFO".to_string(),
     };
 
-    let level: tracing::Level = Level::from_str(log_level.as_str()).unwrap();
+    let level: tracing::Level = Level::from_str(log_level.as_str()).map_err(|e| anyhow!("log_level: {}", e))?;
     let subscriber: SubscriberBuilder = tracing_subscriber::fmt();
     let non_blocking: NonBlocking;
     let _guard: WorkerGuard;
-    (non_blocking, _guard) = tracing_appender::non_blocking(std::io::stdout());
+    // A dry run prints its payloads on stdout, so the logs go elsewhere
+    (non_blocking, _guard) = if cli.dry_run {
+        tracing_appender::non_blocking(std::io::stderr())
+    } else {
+        tracing_appender::non_blocking(std::io::stdout())
+    };
 
     subscriber
         .with_writer(non_blocking)
@@ -123,13 +283,14 @@ async fn main() -> anyhow::Result<()> {
         .compact()
         .init();
 
+    let services = config.services();
     let client = match
         reqwest::Client
             ::builder()
             .brotli(true)
             .gzip(true)
-            .https_only(true)
-            .connection_verbose(true)
+            .https_only(!services.allow_http_loopback)
+            .redirect(services.redirect_policy())
             .use_rustls_tls()
             .build()
     {
@@ -139,288 +300,776 @@ async fn main() -> anyhow::Result<()> {
         }
     };
 
-    let connection = Connection::open(Path::new("videos.sqlite3"))?;
-    connection.execute(
-        "CREATE TABLE IF NOT EXISTS video (id VARCHAR(255) PRIMARY KEY, playlist VARCHAR(255), title VARCHAR(255), author VARCHAR(255), timestamp DATETIME UNIQUE, hooked BOOLEAN DEFAULT 0)",
-        ()
-    )?;
-
-    // Iterate over all the playlists, then store a basic record in sqlite
-    for playlist in &config.playlist {
-        // Fetch the records
-        let results = match
-            client
-                .get(
-                    format!("https://www.youtube.com/feeds/videos.xml?playlist_id={}", playlist.id)
-                )
-                .send().await
-                .expect("failed to get response")
-                .text().await
-        {
-            Ok(response) =>
-                match quick_xml::de::from_str::<Feed>(&response) {
-                    Ok(data) => Some(data),
-                    Err(e) => {
-                        tracing::error!("{}", e.to_string());
-                        None
-                    }
+    let storage = config.storage.clone().unwrap_or_default();
+
+    // Anything that sends or changes deliveries must not run alongside another instance
+    let _lock = match &cli.command {
+        _ if cli.dry_run => None,
+        Some(Command::List { .. }) | Some(Command::TestDestination { .. }) => None,
+        Some(Command::DeadLetter(DeadLetterCommand::List)) => None,
+        _ => {
+            let lock = InstanceLock::acquire(&config.lock_file(), &storage).await?;
+            shutdown::listen();
+            Some(lock)
+        }
+    };
+
+    // A dry run leaves the database as it found it
+    let store = if cli.dry_run {
+        store::open_existing(&storage).await?
+    } else {
+        store::open(&storage).await?
+    };
+
+    let youtube = YouTube::new(
+        client.clone(),
+        config.fetch.clone().unwrap_or_default(),
+        services.clone()
+    );
+    let discord = DiscordSender::new(client.clone(), services.discord_api.clone());
+    let bluesky = BlueskySender::new(client.clone(), services.clone());
+
+    match cli.command {
+        None | Some(Command::Run { .. }) | Some(Command::Daemon) if cli.dry_run => {
+            commands::dry_run(&config, &youtube, store.as_ref()).await
+        }
+        Some(_) if cli.dry_run => Err(anyhow!("--dry-run only applies to run and daemon")),
+        None | Some(Command::Run { .. }) => {
+            run(&config, &youtube, &discord, &bluesky, store.as_ref()).await
+        }
+        Some(Command::Daemon) => {
+            daemon(&config, &source, &client, &youtube, &discord, &bluesky, &store).await
+        }
+        Some(Command::Validate) | Some(Command::Schema) => Ok(()),
+        Some(Command::List { playlist }) => commands::list(store.as_ref(), playlist.as_deref()).await,
+        Some(Command::MarkDelivered { video, playlist, destination }) => {
+            let count = commands::mark_delivered(
+                &config,
+                store.as_ref(),
+                &video,
+                playlist.as_deref(),
+                destination.as_deref()
+            ).await?;
+            println!("Marked {} deliveries of {} as delivered", count, video);
+            Ok(())
+        }
+        Some(Command::Replay { video, destination }) => {
+            let count = commands::replay(
+                &config,
+                &discord,
+                &bluesky,
+                store.as_ref(),
+                &video,
+                destination.as_deref()
+            ).await?;
+            println!("Replayed {} deliveries of {}", count, video);
+            Ok(())
+        }
+        Some(Command::TestDestination { name, video }) => {
+            commands::test_destination(&config, &discord, &bluesky, &name, &video).await
+        }
+        Some(Command::Seed { playlist }) => {
+            let playlist = match config.playlist.iter().find(|p| p.id == playlist) {
+                Some(playlist) => playlist,
+                None => {
+                    return Err(anyhow!("Playlist {} is not configured", playlist));
                 }
+            };
+            let count = seed(&youtube, store.as_ref(), playlist).await?;
+            println!("Seeded playlist {} with {} videos", &playlist.name, count);
+            Ok(())
+        }
+        Some(Command::DeadLetter(DeadLetterCommand::List)) => {
+            for d in store.dead_letters().await? {
+                println!(
+                    "{}\t{}\t{}\t{}\tattempts={}\t{}",
+                    d.video,
+                    d.playlist,
+                    d.destination,
+                    redact(&d.target),
+                    d.attempts,
+                    d.last_error.unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+        Some(Command::DeadLetter(DeadLetterCommand::Requeue { video, destination })) => {
+            let count = store.requeue(video.as_deref(), destination.as_deref()).await?;
+            println!("Requeued {} deliveries", count);
+            Ok(())
+        }
+    }
+}
+
+/// Records every entry currently in the playlist feed as delivered,
+/// so only videos published afterwards are announced
+async fn seed(
+    youtube: &YouTube,
+    store: &dyn VideoStore,
+    playlist: &Playlist
+) -> anyhow::Result<usize> {
+    let feed = match youtube.fetch_feed(playlist, None).await {
+        Some(Fetched::Changed(feed, _)) => feed,
+        _ => {
+            return Err(anyhow!("Unable to fetch the feed for playlist {}", &playlist.id));
+        }
+    };
+
+    for entry in &feed.entry {
+        let id = store::video_id(entry);
+        store.upsert_entry(&playlist.id, entry, true).await?;
+        store.mark_hooked(&id, &playlist.id).await?;
+        store.skip_pending(&id, &playlist.id).await?;
+    }
+    store.mark_known_playlist(&playlist.id).await?;
+
+    Ok(feed.entry.len())
+}
+
+/// Stores the entries of the playlist's feed, when it changed, and makes sure every unhooked
+/// video has a delivery for each sink. With `dedupe`, a sink that already has the video from another
+/// playlist is left out
+async fn ingest(
+    youtube: &YouTube,
+    store: &dyn VideoStore,
+    playlist: &Playlist,
+    feed: Option<&Fetched>,
+    dedupe: bool
+) -> anyhow::Result<()> {
+    let known = store.is_known_playlist(&playlist.id).await?;
+
+    if let Some(Fetched::Unchanged) = feed {
+        metrics().polled(&playlist.id);
+    }
+
+    if let Some(Fetched::Changed(data, cache)) = feed {
+        metrics().polled(&playlist.id);
+
+        // The feed is only cached once everything in it was handled, so a failure is retried
+        // on the next pass instead of the feed being skipped as unchanged
+        let mut settled = true;
+
+        // A playlist seen for the first time without backfill only records what is already there
+        let seed = !known && !playlist.backfill.unwrap_or(true);
+        for entry in &data.entry {
+            match store.upsert_entry(&playlist.id, entry, seed).await {
+                Ok(Upsert::Changed) => {
+                    metrics().entry_ingested(&playlist.id, "changed");
+                    tracing::info!("{} changed, its announcements will be edited", &entry.title);
+                }
+                Ok(Upsert::Inserted) => metrics().entry_ingested(&playlist.id, "inserted"),
+                Ok(Upsert::Unchanged) => metrics().entry_ingested(&playlist.id, "unchanged"),
+                Err(e) => {
+                    tracing::error!("Unable to store {}: {:?}", &entry.id, e);
+                    settled = false;
+                }
+            }
+        }
+
+        if seed {
+            tracing::info!(
+                "Seeded playlist {} with {} existing videos",
+                &playlist.name,
+                data.entry.len()
+            );
+        }
+        store.mark_known_playlist(&playlist.id).await?;
+
+        if playlist.webhooks.iter().any(|w| w.removed_action() != RemovedAction::Ignore) {
+            settled &= detect_removed(youtube, store, playlist, data).await?;
+        }
+
+        if settled {
+            store.save_feed_cache(cache).await?;
+        }
+    }
+
+    for id in store.unhooked_for_playlist(&playlist.id).await? {
+        for webhook in &playlist.webhooks {
+            for target in webhook.targets() {
+                let key = DeliveryKey {
+                    video: &id,
+                    playlist: &playlist.id,
+                    destination: &webhook.destination,
+                    target: &target,
+                };
+                if dedupe && store.has_delivery(&id, &webhook.destination, &target).await? {
+                    continue;
+                }
+                store.enqueue(&key).await?;
+            }
+        }
+
+        // Everything may have gone out through other playlists already
+        if store.pending_count(&id, &playlist.id).await? == 0 {
+            store.mark_hooked(&id, &playlist.id).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Looks for announced videos that are no longer in the playlist. A feed shorter than `FEED_SIZE` lists
+/// the whole playlist, and so does the YouTube Data API when there is an API key, so a video missing
+/// from either was taken out. Every such video is checked with YouTube: one that is gone is flagged as
+/// removed everywhere, one that is still up only from this playlist. A full feed without an API key
+/// only lists part of the playlist, in playlist order, so a video missing from it may just have moved:
+/// those at least as recent as the feed's oldest entry are only flagged when they are gone from YouTube.
+/// Returns false when a candidate couldn't be checked
+async fn detect_removed(
+    youtube: &YouTube,
+    store: &dyn VideoStore,
+    playlist: &Playlist,
+    feed: &Feed
+) -> anyhow::Result<bool> {
+    // YouTube sometimes serves an empty feed for a playlist that isn't
+    let oldest = match feed.entry.iter().map(|e| e.published.as_str()).min() {
+        Some(oldest) => oldest,
+        None => {
+            return Ok(true);
+        }
+    };
+    let listed: HashSet<String> = feed.entry.iter().map(store::video_id).collect();
+    let members = if feed.entry.len() < FEED_SIZE {
+        Some(listed.clone())
+    } else {
+        match youtube.playlist_items(&playlist.id).await {
+            Ok(members) => members,
             Err(e) => {
-                tracing::error!("{}", e.to_string());
-                None
+                tracing::warn!("Unable to list the videos of {}: {:?}", &playlist.name, e);
+                return Ok(false);
             }
+        }
+    };
+    let mut checked = true;
+
+    for v in store.announced_for_playlist(&playlist.id).await? {
+        let left = match &members {
+            Some(members) => !members.contains(&v.id) && !listed.contains(&v.id),
+            None => !listed.contains(&v.id) && v.timestamp.as_str() >= oldest,
         };
+        if !left {
+            continue;
+        }
+
+        match youtube.is_available(&v.id).await {
+            Ok(true) if members.is_none() => {}
+            Ok(true) => {
+                tracing::info!(
+                    "{} was taken out of {}, its announcements there will be retracted",
+                    &v.title,
+                    &playlist.name
+                );
+                store.mark_removed_from(&v.id, &playlist.id).await?;
+            }
+            Ok(false) => {
+                tracing::info!("{} was removed, its announcements will be retracted", &v.title);
+                store.mark_removed(&v.id).await?;
+            }
+            Err(e) => {
+                tracing::warn!("Unable to check whether {} is still available: {:?}", &v.id, e);
+                checked = false;
+            }
+        }
+    }
+
+    Ok(checked)
+}
+
+/// Whether a playlist or destination was paused through the admin API
+async fn is_paused(store: &dyn VideoStore, scope: PauseScope, name: &str) -> anyhow::Result<bool> {
+    Ok(store.pauses().await?.iter().any(|p| p.is(scope, name)))
+}
+
+/// Sends every due delivery of a video, and marks it hooked once nothing is pending anymore
+async fn deliver(
+    config: &Config,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &dyn VideoStore,
+    playlist: &Playlist,
+    v: &Video
+) -> anyhow::Result<()> {
+    let pauses = store.pauses().await?;
+
+    for webhook in &playlist.webhooks {
+        // Deliveries to a paused webhook stay pending until it is resumed
+        if webhook.is_paused(&pauses) {
+            continue;
+        }
+
+        for target in webhook.targets() {
+            // What was sent so far is recorded; the rest stays pending for the next run
+            if shutdown::requested() {
+                return Ok(());
+            }
+
+            let key = DeliveryKey {
+                video: &v.id,
+                playlist: &playlist.id,
+                destination: &webhook.destination,
+                target: &target,
+            };
+
+            if !store.is_due(&key).await? {
+                continue;
+            }
 
-        // Insert the records into the database
-        match results {
-            Some(data) => {
-                for entry in data.entry {
-                    let r = connection.execute(
-                        "INSERT OR IGNORE INTO video (id, title, playlist, timestamp, hooked) VALUES (?1, ?2, ?3, ?4, 0);",
-                        (
-                            &entry.id.replace("yt:video:", ""),
-                            &entry.title,
-                            &playlist.id,
-                            &entry.published,
-                        )
+            let result = match webhook.destination {
+                WebhookType::Discord => {
+                    let message = DiscordSender::render(config, webhook, v);
+                    discord.send(&target, &message, webhook.is_forum.unwrap_or(false)).await
+                }
+                WebhookType::BlueSky =>
+                    match &webhook.credentials {
+                        Some(credentials) => bluesky.post(credentials, v).await,
+                        None => Err(anyhow!("The bluesky webhook has no credentials")),
+                    }
+            };
+
+            match result {
+                Ok(receipt) => {
+                    metrics().delivery(webhook.destination.as_str(), "delivered");
+                    store.mark_delivered(&key, &receipt).await?;
+                }
+                Err(e) => {
+                    tracing::error!(
+                        "Failed to deliver {} to {}: {:?}",
+                        &v.id,
+                        webhook.destination.as_str(),
+                        e
                     );
+                    let status = store
+                        .mark_failed(&key, &e.to_string(), &webhook.retry_policy()).await?;
+                    let outcome = if status == DeliveryStatus::Dead { "dead" } else { "retry" };
+                    metrics().delivery(webhook.destination.as_str(), outcome);
+                    if status == DeliveryStatus::Dead {
+                        tracing::warn!(
+                            "Giving up on {} for {}, moved to dead-letter",
+                            &v.id,
+                            webhook.destination.as_str()
+                        );
+                    }
                 }
             }
-            None => {}
-        }
-    }
-
-    // Once we have all the videos, we need to run the webhooks for them
-    for playlist in &config.playlist {
-        let mut stmt = connection
-            .prepare(
-                &format!(
-                    "SELECT * FROM video WHERE hooked = 0 AND playlist = '{}' ORDER BY timestamp ASC",
-                    &playlist.id
-                )
-            )
-            .unwrap();
-        let videos = stmt
-            .query_map([], |row| {
-                Ok(Video {
-                    id: row.get(0).unwrap(),
-                    playlist: row.get(1).unwrap(),
-                    title: row.get(2).unwrap(),
-                    author: String::from(""),
-                    timestamp: row.get(4).unwrap(),
-                    hooked: row.get(5).unwrap(),
-                })
-            })
-            .unwrap();
-
-        for video in videos {
-            if video.is_ok() {
-                let v = video.unwrap();
-                for webhook in &playlist.webhooks {
-                    match webhook.destination {
-                        WebhookType::Discord => {
-                            let urls = webhook.clone().urls.unwrap();
-                            let groups = webhook.clone().groups.unwrap();
-                            for url in &urls {
-                                let client: WebhookClient = WebhookClient::new(url);
-                                match
-                                    client.send(|message|
-                                        message
-                                            .username(&config.bot.name)
-                                            .avatar_url(&config.bot.icon)
-                                            .content(
-                                                &format!(
-                                                    "{} :: {}",
-                                                    groups.join(" ").as_str(),
-                                                    &format!(
-                                                        "https://www.youtube.com/watch?v={}",
-                                                        &v.id
-                                                    )
-                                                )
-                                            )
-                                            .thread_name(&v.title, webhook.is_forum.unwrap())
-                                            .allow_mentions(
-                                                Some(
-                                                    vec![
-                                                        AllowedMention::UserMention,
-                                                        AllowedMention::RoleMention
-                                                    ]
-                                                ),
-                                                None,
-                                                None,
-                                                false
-                                            )
-                                            .embed(|embed|
-                                                embed
-                                                    .description(
-                                                        &format!(
-                                                            "### [{}]({})",
-                                                            &v.title,
-                                                            &format!(
-                                                                "https://www.youtube.com/watch?v={}",
-                                                                &v.id
-                                                            )
-                                                        )
-                                                    )
-                                                    .color(&"16711680")
-                                                    .image(
-                                                        &format!(
-                                                            "https://img.youtube.com/vi/{}/maxresdefault.jpg",
-                                                            &v.id
-                                                        )
-                                                    )
-                                                    .video(
-                                                        &format!(
-                                                            "https://www.youtube.com/watch?v={}",
-                                                            &v.id
-                                                        )
-                                                    )
-                                                    .thumbnail(
-                                                        &format!(
-                                                            "https://www.iconfinder.com/icons/317714/download/png/256"
-                                                        )
-                                                    )
-                                                    .author(
-                                                        &config.author.name,
-                                                        Some(config.author.clone().url),
-                                                        Some(config.author.clone().icon)
-                                                    )
-                                            )
-                                    ).await
-                                {
-                                    Ok(result) => {
-                                        if result {
-                                            let r = connection.execute(
-                                                &format!(
-                                                    "UPDATE video SET hooked = 1 WHERE id = '{}';",
-                                                    &v.id
-                                                ),
-                                                ()
-                                            );
-                                        }
-                                        _ = tokio::time::sleep(Duration::from_secs(1)).await;
-                                    }
-                                    Err(e) => {
-                                        tracing::error!("{:?}", e);
-                                    }
-                                }
-                            }
+        }
+    }
+
+    // The video is only hooked once every sink has accepted it
+    if store.pending_count(&v.id, &playlist.id).await? == 0 {
+        store.mark_hooked(&v.id, &playlist.id).await?;
+    }
+
+    Ok(())
+}
+
+/// Brings announcements whose video changed since they were sent up to date.
+/// An edit that fails stays flagged and is tried again on the next pass
+async fn edit(
+    config: &Config,
+    discord: &DiscordSender,
+    store: &dyn VideoStore,
+    playlist: &Playlist
+) -> anyhow::Result<()> {
+    let pauses = store.pauses().await?;
+
+    for (v, d) in store.pending_edits(&playlist.id).await? {
+        if shutdown::requested() {
+            break;
+        }
+
+        let message_id = match &d.message_id {
+            Some(message_id) => message_id,
+            None => {
+                continue;
+            }
+        };
+
+        let webhook = playlist.webhooks
+            .iter()
+            .find(|w| w.destination == WebhookType::Discord && w.targets().contains(&d.target));
+        let webhook = match webhook {
+            Some(webhook) if !webhook.is_paused(&pauses) => webhook,
+            _ => {
+                continue;
+            }
+        };
+
+        let message = DiscordSender::render(config, webhook, &v);
+        if let Err(e) = discord.edit(&d.target, message_id, d.thread_id.as_deref(), &message).await {
+            tracing::error!("Unable to edit the announcement for {}: {:?}", &v.id, e);
+            continue;
+        }
+
+        if let Some(thread_id) = &d.thread_id {
+            match &webhook.bot_token {
+                Some(bot_token) => {
+                    if let Err(e) = discord.rename_thread(bot_token, thread_id, &v.title).await {
+                        tracing::error!("Unable to rename the thread for {}: {:?}", &v.id, e);
+                        continue;
+                    }
+                }
+                None => {
+                    tracing::warn!(
+                        "Not renaming the thread for {}, the webhook has no bot_token",
+                        &v.id
+                    );
+                }
+            }
+        }
+
+        let key = DeliveryKey {
+            video: &v.id,
+            playlist: &playlist.id,
+            destination: &webhook.destination,
+            target: &d.target,
+        };
+        store.mark_edited(&key).await?;
+        tracing::info!("Edited the announcement for {}", &v.title);
+    }
+
+    Ok(())
+}
+
+/// Takes down the announcements of removed videos according to each webhook's `on_removed`.
+/// A retraction that fails stays pending and is tried again on the next pass
+async fn retract(
+    config: &Config,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &dyn VideoStore,
+    playlist: &Playlist
+) -> anyhow::Result<()> {
+    let pauses = store.pauses().await?;
+
+    for (v, d) in store.pending_retractions(&playlist.id).await? {
+        if shutdown::requested() {
+            break;
+        }
+
+        let webhook = playlist.webhooks
+            .iter()
+            .find(|w| w.destination.as_str() == d.destination && w.targets().contains(&d.target));
+        let webhook = match webhook {
+            Some(webhook) if !webhook.is_paused(&pauses) => webhook,
+            _ => {
+                continue;
+            }
+        };
+
+        let action = webhook.removed_action();
+        let message_id = match &d.message_id {
+            Some(message_id) if action != RemovedAction::Ignore => message_id,
+            _ => {
+                continue;
+            }
+        };
+
+        let result = match (&webhook.destination, action) {
+            (WebhookType::Discord, RemovedAction::Delete) =>
+                match (&webhook.bot_token, &d.thread_id) {
+                    (Some(bot_token), Some(thread_id)) =>
+                        discord.delete_thread(bot_token, thread_id).await,
+                    _ => discord.delete(&d.target, message_id, d.thread_id.as_deref()).await,
+                }
+            (WebhookType::Discord, _) => {
+                let message = DiscordSender::render_removed(config, &v);
+                discord.edit(&d.target, message_id, d.thread_id.as_deref(), &message).await
+            }
+            (WebhookType::BlueSky, action) =>
+                match (&webhook.credentials, action, &d.record_cid) {
+                    (None, _, _) => Err(anyhow!("The bluesky webhook has no credentials")),
+                    (Some(credentials), RemovedAction::Delete, _) =>
+                        bluesky.delete(credentials, message_id).await,
+                    (Some(credentials), _, Some(cid)) => {
+                        let text = format!("{} is no longer available.", &v.title);
+                        bluesky.reply(credentials, message_id, cid, &text).await
+                    }
+                    (Some(_), _, None) => {
+                        Err(anyhow!("The post's CID wasn't recorded, so it can't be replied to"))
+                    }
+                }
+        };
+
+        if let Err(e) = result {
+            tracing::error!("Unable to retract the announcement for {}: {:?}", &v.id, e);
+            continue;
+        }
+
+        let key = DeliveryKey {
+            video: &v.id,
+            playlist: &playlist.id,
+            destination: &webhook.destination,
+            target: &d.target,
+        };
+        store.mark_retracted(&key).await?;
+        tracing::info!("Retracted the announcement for {}", &v.title);
+    }
+
+    Ok(())
+}
+
+/// Polls one playlist: ingest its feed, send every due delivery, then apply edits and retractions.
+/// `feed` is None when it couldn't be fetched, which still lets pending deliveries go out
+async fn poll(
+    config: &Config,
+    youtube: &YouTube,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &dyn VideoStore,
+    playlist: &Playlist,
+    feed: Option<Fetched>
+) -> anyhow::Result<()> {
+    let status = match &feed {
+        Some(Fetched::Changed(..)) => "fetched",
+        Some(Fetched::Unchanged) => "unchanged",
+        None => "failed",
+    };
+    if let Err(e) = store.record_poll(&playlist.id, status).await {
+        tracing::error!("Unable to record the poll of playlist {}: {:?}", &playlist.name, e);
+    }
+
+    let dedupe = config.dedupe.unwrap_or(false);
+    if let Err(e) = ingest(youtube, store, playlist, feed.as_ref(), dedupe).await {
+        tracing::error!("Unable to ingest playlist {}: {:?}", &playlist.name, e);
+    }
+
+    // Then run the webhooks for every video that still has pending deliveries
+    for v in &store.pending_for_playlist(&playlist.id).await? {
+        if shutdown::requested() {
+            return Ok(());
+        }
+        if let Err(e) = deliver(config, discord, bluesky, store, playlist, v).await {
+            tracing::error!("Unable to deliver {}: {:?}", &v.id, e);
+        }
+    }
+
+    if let Err(e) = edit(config, discord, store, playlist).await {
+        tracing::error!("Unable to edit announcements for playlist {}: {:?}", &playlist.name, e);
+    }
+
+    if let Err(e) = retract(config, discord, bluesky, store, playlist).await {
+        tracing::error!("Unable to retract announcements for playlist {}: {:?}", &playlist.name, e);
+    }
+
+    Ok(())
+}
+
+/// A single pass over every playlist, as `run` or from cron. The feeds are all fetched
+/// concurrently first, then each playlist is ingested and delivered in turn
+async fn run(
+    config: &Config,
+    youtube: &YouTube,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &dyn VideoStore
+) -> anyhow::Result<()> {
+    let pauses = store.pauses().await?;
+    let (playlists, paused): (Vec<Playlist>, Vec<Playlist>) = config.playlist
+        .iter()
+        .cloned()
+        .partition(|p| !pauses.iter().any(|pause| pause.is(PauseScope::Playlist, &p.id)));
+    for playlist in &paused {
+        tracing::info!("Not polling playlist {}, it is paused", &playlist.name);
+    }
+
+    let feeds = youtube.fetch_feeds(&playlists, Some(store)).await;
+    let unchanged = feeds
+        .iter()
+        .filter(|feed| matches!(feed, Some(Fetched::Unchanged)))
+        .count();
+    tracing::info!("{} of {} feeds unchanged since they were last fetched", unchanged, feeds.len());
+
+    for (playlist, feed) in playlists.iter().zip(feeds) {
+        if shutdown::requested() {
+            break;
+        }
+        if let Err(e) = poll(config, youtube, discord, bluesky, store, playlist, feed).await {
+            tracing::error!("Unable to poll playlist {}: {:?}", &playlist.name, e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Polls every playlist on its own interval, reusing the same HTTP client, database
+/// connection and Bluesky sessions for as long as the process runs.
+/// A playlist the WebSub hub pushed entries for, or the admin API asked for, is polled right away
+async fn daemon(
+    config: &Config,
+    source: &Source,
+    client: &reqwest::Client,
+    youtube: &YouTube,
+    discord: &DiscordSender,
+    bluesky: &BlueskySender,
+    store: &Arc<dyn VideoStore>
+) -> anyhow::Result<()> {
+    if config.playlist.is_empty() {
+        tracing::warn!("No playlists are configured, nothing to poll until the configuration is reloaded");
+    }
+
+    // Reloads swap the configuration between two polls, so a poll never sees half of each
+    let mut config = config.clone();
+    let mut settings = config.daemon.clone().unwrap_or_default();
+    let mut schedule = Schedule::new(config.playlist.iter().map(|p| p.id.as_str()));
+    let (playlists, _) = watch::channel(Arc::new(config.playlist.clone()));
+    let mut reloads = source.watch();
+    // WebSub pushes and the admin API wake the loop up to poll a playlist right away
+    let (wake, mut woken) = mpsc::unbounded_channel();
+    if let Some(websub) = &config.websub {
+        websub::start(
+            websub.clone(),
+            config.services().websub_hub,
+            playlists.subscribe(),
+            store.clone(),
+            client.clone(),
+            wake.clone()
+        ).await?;
+    }
+    if let Some(admin) = &config.admin {
+        admin::serve(admin.clone(), playlists.subscribe(), store.clone(), wake.clone()).await?;
+    }
+    if let Some(listener) = &config.metrics {
+        metrics::serve(listener.clone(), playlists.subscribe(), store.clone()).await?;
+    }
+    let store = store.as_ref();
+
+    loop {
+        let id = tokio::select! {
+            id = scheduler::due(schedule.next()) => id,
+            Some(id) = woken.recv() => id,
+            Some(reason) = reloads.recv() => {
+                tracing::info!("Reloading {} after {}", source.path.display(), reason);
+                match source.reload(&config) {
+                    Ok(reloaded) => {
+                        for playlist in reload::removed(&config, &reloaded) {
+                            tracing::info!("Stopped polling playlist {}", &playlist.name);
+                            metrics().forget(&playlist.id);
                         }
-                        WebhookType::BlueSky => {
-                            let credentials = webhook.clone().credentials.unwrap();
-                            match BskyAgent::builder().build().await {
-                                Ok(agent) =>
-                                    match
-                                        agent.login(
-                                            &credentials.username,
-                                            &credentials.password
-                                        ).await
-                                    {
-                                        Ok(session) => {
-                                            let thumbnail = match
-                                                reqwest::get(
-                                                    format!(
-                                                        "https://img.youtube.com/vi/{}/maxresdefault.jpg",
-                                                        &v.id
-                                                    )
-                                                ).await
-                                            {
-                                                Ok(result) =>
-                                                    match
-                                                        agent.api.com.atproto.repo.upload_blob(
-                                                            result.bytes().await.unwrap().to_vec()
-                                                        ).await
-                                                    {
-                                                        Ok(blob) =>
-                                                            match
-                                                                agent.create_record(RecordData {
-                                                                    created_at: BskyDateTime::now(),
-                                                                    embed: Some(
-                                                                        Union::Refs(
-                                                                            RecordEmbedRefs::AppBskyEmbedExternalMain(
-                                                                                Box::new(Main {
-                                                                                    data: MainData {
-                                                                                        external: External {
-                                                                                            data: ExternalData {
-                                                                                                title: v.title.clone(),
-                                                                                                description: v.author.clone(),
-                                                                                                uri: format!(
-                                                                                                    "https://www.youtube.com/watch?v={}",
-                                                                                                    &v.id
-                                                                                                ),
-                                                                                                thumb: Some(
-                                                                                                    blob.blob.clone()
-                                                                                                ),
-                                                                                            },
-                                                                                            extra_data: ipld_core::ipld::Ipld::Null,
-                                                                                        },
-                                                                                    },
-                                                                                    extra_data: ipld_core::ipld::Ipld::Null,
-                                                                                })
-                                                                            )
-                                                                        )
-                                                                    ),
-                                                                    entities: None,
-                                                                    facets: None,
-                                                                    labels: None,
-                                                                    langs: Some(
-                                                                        vec![
-                                                                            Language::new(
-                                                                                String::from(
-                                                                                    "en-US"
-                                                                                )
-                                                                            ).unwrap()
-                                                                        ]
-                                                                    ),
-                                                                    reply: None,
-                                                                    tags: None,
-                                                                    text: format!("{}", &v.title),
-                                                                }).await
-                                                            {
-                                                                Ok(result) => {
-                                                                    let r = connection.execute(
-                                                                        &format!(
-                                                                            "UPDATE video SET hooked = 1 WHERE id = '{}';",
-                                                                            &v.id
-                                                                        ),
-                                                                        ()
-                                                                    );
-                                                                    tracing::info!(
-                                                                        "{}",
-                                                                        &format!(
-                                                                            "Published Video: {} to Bluesky!",
-                                                                            &v.title
-                                                                        )
-                                                                    )
-                                                                }
-                                                                Err(e) => {
-                                                                    tracing::error!("{:?}", e);
-                                                                }
-                                                            }
-
-                                                        Err(e) => {
-                                                            tracing::error!("{:?}", e);
-                                                        }
-                                                    }
-                                                Err(e) => {
-                                                    tracing::error!("{:?}", e);
-                                                }
-                                            };
-                                        }
-                                        Err(e) => {
-                                            tracing::error!("{:?}", e);
-                                        }
-                                    }
-                                Err(e) => {
-                                    tracing::error!("{:?}", e);
-                                }
-                            }
+                        for playlist in reload::removed(&reloaded, &config) {
+                            tracing::info!("Started polling playlist {}", &playlist.name);
                         }
-                        _ => {}
+
+                        schedule.update(reloaded.playlist.iter().map(|p| p.id.as_str()));
+                        playlists.send_replace(Arc::new(reloaded.playlist.clone()));
+                        settings = reloaded.daemon.clone().unwrap_or_default();
+                        config = reloaded;
                     }
+                    Err(e) => tracing::error!("Keeping the current configuration: {:?}", e),
                 }
+                continue;
             }
+            _ = shutdown::wait() => break,
+        };
+
+        let playlist = match config.playlist.iter().find(|p| p.id == id) {
+            Some(playlist) => playlist,
+            None => {
+                continue;
+            }
+        };
+
+        match is_paused(store, PauseScope::Playlist, &playlist.id).await {
+            Ok(true) => tracing::debug!("Not polling playlist {}, it is paused", &playlist.name),
+            Ok(false) => {
+                let feed = youtube.fetch_feed(playlist, Some(store)).await;
+                if let Err(e) = poll(&config, youtube, discord, bluesky, store, playlist, feed).await {
+                    tracing::error!("Unable to poll playlist {}: {:?}", &playlist.name, e);
+                }
+            }
+            Err(e) => tracing::error!("Unable to tell whether {} is paused: {:?}", &playlist.name, e),
+        }
+        if shutdown::requested() {
+            break;
+        }
+
+        let after = settings.interval(playlist.poll_interval);
+        tracing::debug!("Polling playlist {} again in {:?}", &playlist.name, after);
+        schedule.reschedule(&id, after);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::Entry;
+    use crate::store::{ FeedCache, MemoryStore };
+
+    fn playlist(backfill: &str) -> Playlist {
+        serde_yaml::from_str(&format!(
+            "id: PL1
+name: uploads
+{}
+webhooks:
+  - destination: discord
+    urls: [https://discord.com/api/webhooks/1/token]",
+            backfill
+        )).unwrap()
+    }
+
+    fn feed(ids: &[&str]) -> Fetched {
+        let entry = ids
+            .iter()
+            .map(|id| Entry {
+                title: format!("Video {}", id),
+                id: format!("yt:video:{}", id),
+                published: "2024-01-01T00:00:00+00:00".to_string(),
+                updated: "2024-01-01T00:00:00+00:00".to_string(),
+                author: None,
+            })
+            .collect();
+        let cache = FeedCache {
+            url: "https://www.youtube.com/feeds/videos.xml?playlist_id=PL1".to_string(),
+            etag: None,
+            last_modified: None,
+            hash: ids.join(","),
+        };
+        Fetched::Changed(Feed { entry, ..Feed::default() }, cache)
+    }
+
+    fn youtube() -> YouTube {
+        YouTube::new(reqwest::Client::new(), FetchConfig::default(), ServicesConfig::default())
+    }
+
+    #[tokio::test]
+    async fn seeds_a_new_playlist_without_backfill() {
+        let store = MemoryStore::default();
+        let playlist = playlist("backfill: false");
+
+        ingest(&youtube(), &store, &playlist, Some(&feed(&["a", "b"])), false).await.unwrap();
+        assert!(store.is_known_playlist("PL1").await.unwrap());
+        assert!(store.unhooked_for_playlist("PL1").await.unwrap().is_empty());
+        assert!(store.deliveries("a").await.unwrap().is_empty());
+        assert!(store.deliveries("b").await.unwrap().is_empty());
+
+        // Once the playlist is known, only what is new to it is announced
+        ingest(&youtube(), &store, &playlist, Some(&feed(&["c", "a", "b"])), false).await.unwrap();
+        assert_eq!(store.unhooked_for_playlist("PL1").await.unwrap(), vec!["c".to_string()]);
+        assert_eq!(store.deliveries("c").await.unwrap().len(), 1);
+        assert!(store.deliveries("a").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn backfills_a_new_playlist_by_default() {
+        let store = MemoryStore::default();
+
+        ingest(&youtube(), &store, &playlist(""), Some(&feed(&["a", "b"])), false).await.unwrap();
+        for id in ["a", "b"] {
+            let deliveries = store.deliveries(id).await.unwrap();
+            assert_eq!(deliveries.len(), 1, "{}", id);
+            assert_eq!(deliveries[0].status, DeliveryStatus::Pending.as_str());
         }
     }
-    return Ok(());
+
+    #[tokio::test]
+    async fn backfill_only_applies_to_the_first_poll() {
+        let store = MemoryStore::default();
+        store.mark_known_playlist("PL1").await.unwrap();
+
+        ingest(&youtube(), &store, &playlist("backfill: false"), Some(&feed(&["a"])), false).await.unwrap();
+        assert_eq!(store.unhooked_for_playlist("PL1").await.unwrap(), vec!["a".to_string()]);
+        assert_eq!(store.deliveries("a").await.unwrap().len(), 1);
+    }
 }
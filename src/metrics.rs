@@ -0,0 +1,293 @@
+use axum::extract::State;
+use axum::http::{ header, StatusCode };
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use prometheus::{
+    Encoder,
+    GaugeVec,
+    Histogram,
+    HistogramOpts,
+    HistogramVec,
+    IntCounterVec,
+    Opts,
+    Registry,
+    TextEncoder,
+};
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex, OnceLock };
+use std::time::{ Duration, Instant };
+
+use crate::reload::Playlists;
+use crate::store::{ PauseScope, VideoStore };
+use crate::Playlist;
+
+/// The health and metrics listener of daemon mode
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MetricsConfig {
+    #[serde(default = "MetricsConfig::default_listen")]
+    pub listen: String,
+}
+
+impl MetricsConfig {
+    fn default_listen() -> String {
+        "0.0.0.0:9090".to_string()
+    }
+}
+
+/// Everything the relay reports on `/metrics`
+pub struct Metrics {
+    registry: Registry,
+    feed_fetch_seconds: HistogramVec,
+    feed_fetches: IntCounterVec,
+    feed_cache_hits: IntCounterVec,
+    entries_ingested: IntCounterVec,
+    deliveries: IntCounterVec,
+    rate_limit_wait_seconds: Histogram,
+    seconds_since_last_poll: GaugeVec,
+    /// When each playlist's feed was last fetched and parsed
+    last_polls: Mutex<HashMap<String, Instant>>,
+}
+
+/// The process-wide metrics
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("relay".to_string()), None).expect(
+            "valid metrics registry"
+        );
+
+        let feed_fetch_seconds = HistogramVec::new(
+            HistogramOpts::new("feed_fetch_seconds", "How long fetching a playlist feed took"),
+            &["playlist"]
+        ).expect("valid metric");
+        let feed_fetches = IntCounterVec::new(
+            Opts::new("feed_fetches_total", "Playlist feed fetches, by HTTP status or error"),
+            &["playlist", "status"]
+        ).expect("valid metric");
+        let feed_cache_hits = IntCounterVec::new(
+            Opts::new(
+                "feed_cache_hits_total",
+                "Feeds that hadn't changed, by how that was found out (not_modified, identical)"
+            ),
+            &["playlist", "kind"]
+        ).expect("valid metric");
+        let entries_ingested = IntCounterVec::new(
+            Opts::new("entries_ingested_total", "Feed entries stored, by what storing them did"),
+            &["playlist", "result"]
+        ).expect("valid metric");
+        let deliveries = IntCounterVec::new(
+            Opts::new(
+                "deliveries_total",
+                "Delivery attempts, by destination and outcome (delivered, retry, dead)"
+            ),
+            &["destination", "outcome"]
+        ).expect("valid metric");
+        let rate_limit_wait_seconds = Histogram::with_opts(
+            HistogramOpts::new(
+                "discord_rate_limit_wait_seconds",
+                "Time spent waiting out Discord rate limits before a request"
+            )
+        ).expect("valid metric");
+        let seconds_since_last_poll = GaugeVec::new(
+            Opts::new(
+                "seconds_since_last_poll",
+                "Seconds since a playlist's feed was last fetched successfully"
+            ),
+            &["playlist"]
+        ).expect("valid metric");
+
+        registry.register(Box::new(feed_fetch_seconds.clone())).expect("unique metric");
+        registry.register(Box::new(feed_fetches.clone())).expect("unique metric");
+        registry.register(Box::new(feed_cache_hits.clone())).expect("unique metric");
+        registry.register(Box::new(entries_ingested.clone())).expect("unique metric");
+        registry.register(Box::new(deliveries.clone())).expect("unique metric");
+        registry.register(Box::new(rate_limit_wait_seconds.clone())).expect("unique metric");
+        registry.register(Box::new(seconds_since_last_poll.clone())).expect("unique metric");
+
+        Self {
+            registry,
+            feed_fetch_seconds,
+            feed_fetches,
+            feed_cache_hits,
+            entries_ingested,
+            deliveries,
+            rate_limit_wait_seconds,
+            seconds_since_last_poll,
+            last_polls: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Records a feed fetch. `status` is the HTTP status, or `error` when there was no response
+    pub fn feed_fetched(&self, playlist: &str, status: &str, elapsed: Duration) {
+        self.feed_fetch_seconds.with_label_values(&[playlist]).observe(elapsed.as_secs_f64());
+        self.feed_fetches.with_label_values(&[playlist, status]).inc();
+    }
+
+    /// Records a feed that hadn't changed. `kind` is `not_modified` for a 304, `identical` for the same body
+    pub fn feed_cache_hit(&self, playlist: &str, kind: &str) {
+        self.feed_cache_hits.with_label_values(&[playlist, kind]).inc();
+    }
+
+    /// Records that a playlist's feed was fetched and parsed
+    pub fn polled(&self, playlist: &str) {
+        self.last_polls.lock().unwrap().insert(playlist.to_string(), Instant::now());
+    }
+
+    pub fn entry_ingested(&self, playlist: &str, result: &str) {
+        self.entries_ingested.with_label_values(&[playlist, result]).inc();
+    }
+
+    pub fn delivery(&self, destination: &str, outcome: &str) {
+        self.deliveries.with_label_values(&[destination, outcome]).inc();
+    }
+
+    pub fn rate_limit_waited(&self, wait: Duration) {
+        self.rate_limit_wait_seconds.observe(wait.as_secs_f64());
+    }
+
+    /// Whether every playlist has been polled successfully at least once
+    fn is_ready(&self, playlists: &[Playlist]) -> bool {
+        let last_polls = self.last_polls.lock().unwrap();
+        playlists.iter().all(|playlist| last_polls.contains_key(&playlist.id))
+    }
+
+    /// Stops reporting on a playlist a reload removed
+    pub fn forget(&self, playlist: &str) {
+        self.last_polls.lock().unwrap().remove(playlist);
+        let _ = self.seconds_since_last_poll.remove_label_values(&[playlist]);
+    }
+
+    /// Renders every metric in the Prometheus text format
+    fn render(&self) -> anyhow::Result<Vec<u8>> {
+        for (playlist, at) in self.last_polls.lock().unwrap().iter() {
+            self.seconds_since_last_poll
+                .with_label_values(&[playlist])
+                .set(at.elapsed().as_secs_f64());
+        }
+
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+/// Serves `/healthz`, `/readyz` and `/metrics`. The relay is ready once every one of
+/// `playlists` that isn't paused has been polled successfully
+pub async fn serve(config: MetricsConfig, playlists: Playlists, store: Arc<dyn VideoStore>) -> anyhow::Result<()> {
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(render))
+        .with_state(Arc::new(Readiness { playlists, store }));
+    let listener = tokio::net::TcpListener::bind(&config.listen).await?;
+    tracing::info!("Serving health checks and metrics on {}", &config.listen);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("The metrics server stopped: {:?}", e);
+        }
+    });
+
+    Ok(())
+}
+
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+/// What `/readyz` looks at
+struct Readiness {
+    playlists: Playlists,
+    store: Arc<dyn VideoStore>,
+}
+
+async fn readyz(State(readiness): State<Arc<Readiness>>) -> (StatusCode, &'static str) {
+    let pauses = match readiness.store.pauses().await {
+        Ok(pauses) => pauses,
+        Err(e) => {
+            tracing::error!("Unable to read the pauses: {:?}", e);
+            return (StatusCode::SERVICE_UNAVAILABLE, "unable to read the pauses");
+        }
+    };
+
+    // A paused playlist isn't polled, so it can't hold readiness back
+    let playlists: Vec<Playlist> = readiness.playlists
+        .borrow()
+        .iter()
+        .filter(|p| !pauses.iter().any(|pause| pause.is(PauseScope::Playlist, &p.id)))
+        .cloned()
+        .collect();
+    if metrics().is_ready(&playlists) {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "waiting for the first poll")
+    }
+}
+
+async fn render() -> impl IntoResponse {
+    match metrics().render() {
+        Ok(body) => {
+            let content_type = TextEncoder::new().format_type().to_string();
+            (StatusCode::OK, [(header::CONTENT_TYPE, content_type)], body)
+        }
+        Err(e) => {
+            tracing::error!("Unable to render metrics: {:?}", e);
+            let content_type = "text/plain".to_string();
+            (StatusCode::INTERNAL_SERVER_ERROR, [(header::CONTENT_TYPE, content_type)], Vec::new())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use tokio::sync::watch;
+
+    fn playlist(id: &str) -> Playlist {
+        serde_yaml::from_str(&format!(
+            "id: {id}
+name: uploads
+webhooks:
+  - destination: discord
+    urls: [https://discord.com/api/webhooks/1/token]"
+        )).unwrap()
+    }
+
+    #[tokio::test]
+    async fn waits_for_every_playlist_that_isnt_paused() {
+        // The metrics are process-wide, so these ids are only used here
+        let (_, playlists) = watch::channel(Arc::new(vec![playlist("PL-ready-1"), playlist("PL-ready-2")]));
+        let store = Arc::new(MemoryStore::default());
+        let readiness = Arc::new(Readiness { playlists, store: store.clone() });
+
+        let (status, _) = readyz(State(readiness.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        metrics().polled("PL-ready-1");
+        let (status, _) = readyz(State(readiness.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        // A paused playlist isn't polled, so it doesn't hold readiness back
+        store.set_paused(PauseScope::Playlist, "PL-ready-2", true).await.unwrap();
+        let (status, _) = readyz(State(readiness.clone())).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // Once resumed, it's waited for again until it is polled
+        store.set_paused(PauseScope::Playlist, "PL-ready-2", false).await.unwrap();
+        let (status, _) = readyz(State(readiness.clone())).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+        metrics().polled("PL-ready-2");
+        let (status, body) = readyz(State(readiness)).await;
+        assert_eq!((status, body), (StatusCode::OK, "ready"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::path::{ Path, PathBuf };
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime };
+use tokio::sync::mpsc::{ self, UnboundedReceiver, UnboundedSender };
+use tokio::sync::watch;
+
+use crate::config::{ self, ConfigFormat };
+use crate::secrets::Secrets;
+use crate::store::StorageConfig;
+use crate::{ Config, Playlist };
+
+/// How often daemon mode looks at the configuration file's modification time
+const WATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The playlists daemon mode currently serves. A reload replaces them, and the WebSub,
+/// admin and metrics servers look at the latest ones on every request
+pub type Playlists = watch::Receiver<Arc<Vec<Playlist>>>;
+
+/// Where the configuration comes from, so daemon mode can read it again
+pub struct Source {
+    pub path: PathBuf,
+    pub format: Option<ConfigFormat>,
+    /// `--db`, which keeps overriding `storage.dsn` across reloads
+    pub db: Option<String>,
+    /// Resolves the secret references of every load, reloads included
+    pub secrets: Secrets,
+}
+
+impl Source {
+    /// Reads, resolves and validates the configuration file
+    pub fn load(&self) -> anyhow::Result<Config> {
+        let mut config = config::load(&self.path, self.format, &self.secrets)?;
+
+        if let Some(db) = &self.db {
+            let storage = config.storage.get_or_insert_with(StorageConfig::default);
+            storage.dsn = Some(db.clone());
+        }
+
+        // Nothing is fetched or sent until the whole configuration is known to be usable
+        config::check(&self.path, &config)?;
+        Ok(config)
+    }
+
+    /// Reads the configuration again for a running daemon. Settings that are only read at start up
+    /// keep their current value, with a warning when the file changed them
+    pub fn reload(&self, current: &Config) -> anyhow::Result<Config> {
+        let mut reloaded = self.load()?;
+
+        let mut kept = Vec::new();
+        keep("log_level", &current.log_level, &mut reloaded.log_level, &mut kept);
+        keep("storage", &current.storage, &mut reloaded.storage, &mut kept);
+        keep("lock_file", &current.lock_file, &mut reloaded.lock_file, &mut kept);
+        keep("fetch", &current.fetch, &mut reloaded.fetch, &mut kept);
+        keep("services", &current.services, &mut reloaded.services, &mut kept);
+        keep("websub", &current.websub, &mut reloaded.websub, &mut kept);
+        keep("metrics", &current.metrics, &mut reloaded.metrics, &mut kept);
+        keep("admin", &current.admin, &mut reloaded.admin, &mut kept);
+        if !kept.is_empty() {
+            tracing::warn!("Changes to {} only take effect after a restart", kept.join(", "));
+        }
+
+        Ok(reloaded)
+    }
+
+    /// Asks for a reload on SIGHUP, and whenever the file's modification time changes.
+    /// Each request says what prompted it
+    pub fn watch(&self) -> UnboundedReceiver<&'static str> {
+        let (requests, requested) = mpsc::unbounded_channel();
+        hangups(requests.clone());
+
+        let path = self.path.clone();
+        tokio::spawn(async move {
+            let mut last = modified(&path);
+            loop {
+                tokio::time::sleep(WATCH_INTERVAL).await;
+
+                let now = modified(&path);
+                if now != last {
+                    last = now;
+                    if requests.send("a change to the file").is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        requested
+    }
+}
+
+/// The playlists of `before` that `after` doesn't have
+pub fn removed<'a>(before: &'a Config, after: &Config) -> Vec<&'a Playlist> {
+    before.playlist
+        .iter()
+        .filter(|p| !after.playlist.iter().any(|kept| kept.id == p.id))
+        .collect()
+}
+
+/// Puts back the current value of a setting a reload can't apply, and remembers its name
+fn keep<T: Clone + PartialEq>(name: &'static str, current: &T, reloaded: &mut T, kept: &mut Vec<&'static str>) {
+    if current != reloaded {
+        *reloaded = current.clone();
+        kept.push(name);
+    }
+}
+
+/// Follows symlinks, so swapping a mounted ConfigMap counts as a change
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(unix)]
+fn hangups(requests: UnboundedSender<&'static str>) {
+    use tokio::signal::unix::{ self, SignalKind };
+
+    let mut hangup = match unix::signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            tracing::error!("Unable to listen for SIGHUP: {:?}", e);
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
+            if requests.send("SIGHUP").is_err() {
+                return;
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn hangups(_requests: UnboundedSender<&'static str>) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::sync::Mutex;
+
+    const HEADER: &str =
+        "author: { name: Author, url: https://www.youtube.com/@author, icon: https://example.com/icon.png }
+bot: { name: Bot, url: https://example.com, icon: https://example.com/bot.png }
+";
+
+    fn playlists(ids: &[&str]) -> String {
+        let mut yaml = String::from("playlist:\n");
+        for id in ids {
+            yaml.push_str(&format!(
+                "  - id: {id}
+    name: {id}
+    webhooks:
+      - destination: discord
+        urls: [https://discord.com/api/webhooks/1/token]
+"
+            ));
+        }
+        yaml
+    }
+
+    /// A configuration file of its own for each test
+    struct File(PathBuf);
+
+    impl File {
+        fn new(name: &str, contents: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("relay-reload-{}-{}.yaml", name, std::process::id()));
+            let file = Self(path);
+            file.write(contents);
+            file
+        }
+
+        fn write(&self, contents: &str) {
+            fs::write(&self.0, contents).unwrap();
+        }
+
+        fn source(&self) -> Source {
+            Source { path: self.0.clone(), format: None, db: None, secrets: Secrets::default() }
+        }
+    }
+
+    impl Drop for File {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    #[derive(Clone, Default)]
+    struct Logs(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Logs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Reloads while recording what is logged
+    fn reload(source: &Source, current: &Config) -> (anyhow::Result<Config>, String) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        let reloaded = tracing::subscriber::with_default(subscriber, || source.reload(current));
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        (reloaded, logs)
+    }
+
+    #[test]
+    fn an_invalid_file_leaves_the_current_configuration() {
+        let file = File::new("invalid", &format!("{HEADER}{}", playlists(&["PL1"])));
+        let source = file.source();
+        let current = source.load().unwrap();
+
+        file.write(&format!("{HEADER}playlist:\n  - id: PL1\n    name: uploads\n    unknown: true\n"));
+        assert!(source.reload(&current).is_err());
+        file.write(&format!("{HEADER}{}", playlists(&["PL1"]).replace("/1/token", "/1")));
+        assert!(source.reload(&current).is_err());
+    }
+
+    #[test]
+    fn keeps_what_only_applies_at_start_up() {
+        let file = File::new("keeps", &format!("{HEADER}storage: {{ backend: memory }}\n{}", playlists(&["PL1"])));
+        let source = file.source();
+        let current = source.load().unwrap();
+
+        file.write(&format!(
+            "{HEADER}storage: {{ backend: sqlite, dsn: other.db }}
+metrics: {{ listen: '127.0.0.1:9999' }}
+admin: {{ token: secret }}
+{}",
+            playlists(&["PL1", "PL2"])
+        ));
+        let (reloaded, logs) = reload(&source, &current);
+        let reloaded = reloaded.unwrap();
+
+        assert_eq!(reloaded.storage, current.storage);
+        assert_eq!(reloaded.metrics, None);
+        assert_eq!(reloaded.admin, None);
+        assert_eq!(reloaded.playlist.len(), 2);
+        assert!(logs.contains("WARN"), "{}", logs);
+        assert!(logs.contains("Changes to storage, metrics, admin only take effect after a restart"), "{}", logs);
+    }
+
+    #[test]
+    fn warns_only_when_something_was_kept() {
+        let file = File::new("quiet", &format!("{HEADER}{}", playlists(&["PL1"])));
+        let source = file.source();
+        let current = source.load().unwrap();
+
+        file.write(&format!("{HEADER}{}", playlists(&["PL2"])));
+        let (reloaded, logs) = reload(&source, &current);
+        assert_eq!(reloaded.unwrap().playlist[0].id, "PL2");
+        assert!(!logs.contains("WARN"), "{}", logs);
+    }
+
+    #[test]
+    fn finds_removed_playlists() {
+        let file = File::new("removed", &format!("{HEADER}{}", playlists(&["PL1", "PL2"])));
+        let source = file.source();
+        let before = source.load().unwrap();
+        file.write(&format!("{HEADER}{}", playlists(&["PL2", "PL3"])));
+        let after = source.load().unwrap();
+
+        let dropped: Vec<&str> = removed(&before, &after).iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(dropped, vec!["PL1"]);
+        let added: Vec<&str> = removed(&after, &before).iter().map(|p| p.id.as_str()).collect();
+        assert_eq!(added, vec!["PL3"]);
+    }
+}
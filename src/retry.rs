@@ -0,0 +1,105 @@
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::time::Duration;
+
+/// How often, and how far apart, a failed delivery is retried before it is dead-lettered
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    #[serde(default = "RetryPolicy::default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay in seconds after the first failure. Doubles with every further failure
+    #[serde(default = "RetryPolicy::default_base_delay")]
+    pub base_delay: u64,
+    /// Upper bound in seconds for the delay between two attempts
+    #[serde(default = "RetryPolicy::default_max_delay")]
+    pub max_delay: u64,
+    /// Fraction (0.0 - 1.0) of the delay that is randomly shaved off so sinks that failed together don't retry together
+    #[serde(default = "RetryPolicy::default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Self::default_max_attempts(),
+            base_delay: Self::default_base_delay(),
+            max_delay: Self::default_max_delay(),
+            jitter: Self::default_jitter(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn default_max_attempts() -> u32 {
+        5
+    }
+
+    fn default_base_delay() -> u64 {
+        60
+    }
+
+    fn default_max_delay() -> u64 {
+        3600
+    }
+
+    fn default_jitter() -> f64 {
+        0.2
+    }
+
+    /// Whether a delivery that has failed `attempts` times should be dead-lettered
+    pub fn is_exhausted(&self, attempts: u32) -> bool {
+        attempts >= self.max_attempts
+    }
+
+    /// How long to wait before the next attempt, after `attempts` failures
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exponent = attempts.saturating_sub(1).min(32);
+        let delay = self.base_delay.saturating_mul(1u64 << exponent).min(self.max_delay);
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            1.0 - rand::thread_rng().gen_range(0.0..=jitter)
+        } else {
+            1.0
+        };
+
+        Duration::from_secs_f64((delay as f64) * factor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(jitter: f64) -> RetryPolicy {
+        RetryPolicy { max_attempts: 5, base_delay: 60, max_delay: 600, jitter }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_delay() {
+        let policy = policy(0.0);
+        assert_eq!(policy.backoff(1), Duration::from_secs(60));
+        assert_eq!(policy.backoff(2), Duration::from_secs(120));
+        assert_eq!(policy.backoff(4), Duration::from_secs(480));
+        assert_eq!(policy.backoff(5), Duration::from_secs(600));
+        assert_eq!(policy.backoff(1000), Duration::from_secs(600));
+    }
+
+    #[test]
+    fn jitter_only_shortens_the_delay() {
+        let policy = policy(0.2);
+        for _ in 0..100 {
+            let delay = policy.backoff(2);
+            assert!(delay >= Duration::from_secs(96) && delay <= Duration::from_secs(120), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn exhausted_after_max_attempts() {
+        let policy = policy(0.0);
+        assert!(!policy.is_exhausted(4));
+        assert!(policy.is_exhausted(5));
+    }
+}
@@ -0,0 +1,132 @@
+use rand::Rng;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::collections::{ HashMap, HashSet };
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often `daemon` mode polls the playlists
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Seconds between two polls of a playlist that doesn't set its own `poll_interval`
+    #[serde(default = "DaemonConfig::default_poll_interval")]
+    pub poll_interval: u64,
+    /// Fraction (0.0 - 1.0) of the interval randomly added or taken off, so playlists don't poll in lockstep
+    #[serde(default = "DaemonConfig::default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Self::default_poll_interval(),
+            jitter: Self::default_jitter(),
+        }
+    }
+}
+
+impl DaemonConfig {
+    fn default_poll_interval() -> u64 {
+        300
+    }
+
+    fn default_jitter() -> f64 {
+        0.1
+    }
+
+    /// How long to wait before polling a playlist again, given its own `poll_interval` if it has one
+    pub fn interval(&self, poll_interval: Option<u64>) -> Duration {
+        let interval = poll_interval.unwrap_or(self.poll_interval).max(1) as f64;
+
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        let factor = if jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-jitter..=jitter)
+        } else {
+            1.0
+        };
+
+        Duration::from_secs_f64((interval * factor).max(1.0))
+    }
+}
+
+/// When each playlist is next due to be polled
+#[derive(Debug, Default)]
+pub struct Schedule {
+    due: HashMap<String, Instant>,
+}
+
+impl Schedule {
+    /// Every playlist starts out due right away
+    pub fn new<'a>(playlists: impl IntoIterator<Item = &'a str>) -> Self {
+        let now = Instant::now();
+        Self {
+            due: playlists
+                .into_iter()
+                .map(|playlist| (playlist.to_string(), now))
+                .collect(),
+        }
+    }
+
+    /// The playlist that is due soonest, and when
+    pub fn next(&self) -> Option<(String, Instant)> {
+        self.due
+            .iter()
+            .min_by_key(|(_, at)| **at)
+            .map(|(playlist, at)| (playlist.clone(), *at))
+    }
+
+    pub fn reschedule(&mut self, playlist: &str, after: Duration) {
+        self.due.insert(playlist.to_string(), Instant::now() + after);
+    }
+
+    /// Follows a reload: new playlists are due right away, and removed ones are dropped.
+    /// Playlists that stay keep their place
+    pub fn update<'a>(&mut self, playlists: impl IntoIterator<Item = &'a str>) {
+        let now = Instant::now();
+        let playlists: HashSet<&str> = playlists.into_iter().collect();
+
+        self.due.retain(|playlist, _| playlists.contains(playlist.as_str()));
+        for playlist in playlists {
+            self.due.entry(playlist.to_string()).or_insert(now);
+        }
+    }
+}
+
+/// Resolves with the playlist once it is due, or never when there is none
+pub async fn due(next: Option<(String, Instant)>) -> String {
+    match next {
+        Some((playlist, at)) => {
+            tokio::time::sleep_until(at).await;
+            playlist
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_reloads() {
+        let mut schedule = Schedule::new(["PL1", "PL2"]);
+        schedule.reschedule("PL1", Duration::from_secs(60));
+        schedule.reschedule("PL2", Duration::from_secs(120));
+        let pl1 = schedule.due["PL1"];
+
+        schedule.update(["PL1", "PL3"]);
+        assert!(!schedule.due.contains_key("PL2"));
+        assert_eq!(schedule.due["PL1"], pl1);
+        // The added playlist is due right away, before the one that stayed
+        assert_eq!(schedule.next().unwrap().0, "PL3");
+        assert!(schedule.due["PL3"] <= Instant::now());
+    }
+
+    #[test]
+    fn an_empty_reload_leaves_nothing_due() {
+        let mut schedule = Schedule::new(["PL1"]);
+        schedule.update([]);
+        assert_eq!(schedule.next(), None);
+    }
+}
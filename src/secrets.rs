@@ -0,0 +1,241 @@
+use anyhow::anyhow;
+use reqwest::Url;
+use std::fs;
+
+use crate::Config;
+
+/// Looks secrets up in one backend. A `${scheme:reference}` in a secret field of the
+/// configuration is handed to the resolver registered for `scheme`
+pub trait SecretResolver: Send + Sync {
+    fn scheme(&self) -> &str;
+
+    /// The secret behind `reference`. Errors may name the reference but never the secret
+    fn resolve(&self, reference: &str) -> anyhow::Result<String>;
+}
+
+/// `${NAME}` or `${env:NAME}`, read from the environment
+pub struct EnvResolver;
+
+impl SecretResolver for EnvResolver {
+    fn scheme(&self) -> &str {
+        "env"
+    }
+
+    fn resolve(&self, reference: &str) -> anyhow::Result<String> {
+        std::env::var(reference).map_err(|e| anyhow!("environment variable {}: {}", reference, e))
+    }
+}
+
+/// `${file:/run/secrets/name}`, the contents of a file without its trailing newline
+pub struct FileResolver;
+
+impl SecretResolver for FileResolver {
+    fn scheme(&self) -> &str {
+        "file"
+    }
+
+    fn resolve(&self, reference: &str) -> anyhow::Result<String> {
+        read(reference)
+    }
+}
+
+/// The resolvers secret fields are interpolated with. Environment variables and files are
+/// always there; another backend is one more resolver in the list `default` builds
+pub struct Secrets {
+    resolvers: Vec<Box<dyn SecretResolver>>,
+}
+
+impl Default for Secrets {
+    fn default() -> Self {
+        Self {
+            resolvers: vec![Box::new(EnvResolver), Box::new(FileResolver)],
+        }
+    }
+}
+
+impl Secrets {
+    /// Replaces every `${...}` in `value` with the secret it refers to. `$${` is a literal `${`
+    pub fn interpolate(&self, value: &str) -> anyhow::Result<String> {
+        let mut interpolated = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            if rest[..start].ends_with('$') {
+                interpolated.push_str(&rest[..start - 1]);
+                interpolated.push_str("${");
+                rest = &rest[start + 2..];
+                continue;
+            }
+
+            interpolated.push_str(&rest[..start]);
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => {
+                    return Err(anyhow!("unterminated ${{"));
+                }
+            };
+            interpolated.push_str(&self.resolve(&rest[start + 2..end])?);
+            rest = &rest[end + 1..];
+        }
+        interpolated.push_str(rest);
+
+        Ok(interpolated)
+    }
+
+    fn resolve(&self, reference: &str) -> anyhow::Result<String> {
+        let (scheme, reference) = reference.split_once(':').unwrap_or(("env", reference));
+        match self.resolvers.iter().find(|r| r.scheme() == scheme) {
+            Some(resolver) => resolver.resolve(reference),
+            None => Err(anyhow!("no secret resolver handles ${{{}:...}}", scheme)),
+        }
+    }
+}
+
+/// Fills in the secrets of a freshly parsed configuration: interpolates the fields that may
+/// hold one, and reads `password_file` and `urls_file`. Errors name the field, never its value
+pub fn resolve(config: &mut Config, secrets: &Secrets) -> anyhow::Result<()> {
+    for (i, playlist) in config.playlist.iter_mut().enumerate() {
+        for (j, webhook) in playlist.webhooks.iter_mut().enumerate() {
+            let path = format!("playlist[{}].webhooks[{}]", i, j);
+
+            if let Some(file) = &webhook.urls_file {
+                let urls = read(file).map_err(|e| anyhow!("{}.urls_file: {}", path, e))?;
+                webhook.urls
+                    .get_or_insert_with(Vec::new)
+                    .extend(
+                        urls
+                            .lines()
+                            .map(str::trim)
+                            .filter(|url| !url.is_empty() && !url.starts_with('#'))
+                            .map(str::to_string)
+                    );
+            }
+            for (k, url) in webhook.urls.iter_mut().flatten().enumerate() {
+                interpolate(secrets, &format!("{}.urls[{}]", path, k), url)?;
+            }
+            if let Some(bot_token) = &mut webhook.bot_token {
+                interpolate(secrets, &format!("{}.bot_token", path), bot_token)?;
+            }
+
+            if let Some(credentials) = &mut webhook.credentials {
+                let path = format!("{}.credentials", path);
+                match &credentials.password_file {
+                    Some(_) if !credentials.password.is_empty() => {
+                        return Err(anyhow!("{}: set either password or password_file", path));
+                    }
+                    Some(file) => {
+                        let password = read(file).map_err(|e| anyhow!("{}.password_file: {}", path, e))?;
+                        credentials.password = password;
+                    }
+                    None => {
+                        interpolate(secrets, &format!("{}.password", path), &mut credentials.password)?;
+                    }
+                }
+                if credentials.password.is_empty() {
+                    return Err(anyhow!("{}: password or password_file is required", path));
+                }
+            }
+        }
+    }
+
+    if let Some(websub) = &mut config.websub {
+        interpolate(secrets, "websub.secret", &mut websub.secret)?;
+    }
+    if let Some(admin) = &mut config.admin {
+        interpolate(secrets, "admin.token", &mut admin.token)?;
+    }
+    if let Some(dsn) = config.storage.as_mut().and_then(|storage| storage.dsn.as_mut()) {
+        interpolate(secrets, "storage.dsn", dsn)?;
+    }
+    if let Some(api_key) = config.fetch.as_mut().and_then(|fetch| fetch.api_key.as_mut()) {
+        interpolate(secrets, "fetch.api_key", api_key)?;
+    }
+
+    Ok(())
+}
+
+fn interpolate(secrets: &Secrets, path: &str, value: &mut String) -> anyhow::Result<()> {
+    *value = secrets.interpolate(value).map_err(|e| anyhow!("{}: {}", path, e))?;
+    Ok(())
+}
+
+/// A secret file without its trailing newline
+fn read(path: &str) -> anyhow::Result<String> {
+    let contents = fs::read_to_string(path).map_err(|e| anyhow!("unable to read {}: {}", path, e))?;
+    Ok(contents.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// A delivery target safe to print: the token at the end of a webhook URL is masked.
+/// Webhook URLs carry their token, so every target that is logged, listed or returned goes
+/// through here first. Targets that aren't URLs, like Bluesky handles, are returned as they are
+pub fn redact(target: &str) -> String {
+    match Url::parse(target) {
+        Ok(url) => {
+            let kept = url.path().rsplit_once('/').map_or("", |(kept, _)| kept);
+            format!("{}{}/***", url.origin().ascii_serialization(), kept)
+        }
+        Err(_) => target.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixed;
+
+    impl SecretResolver for Fixed {
+        fn scheme(&self) -> &str {
+            "fixed"
+        }
+
+        fn resolve(&self, reference: &str) -> anyhow::Result<String> {
+            match reference {
+                "token" => Ok("s3cr3t".to_string()),
+                _ => Err(anyhow!("no secret called {}", reference)),
+            }
+        }
+    }
+
+    fn secrets() -> Secrets {
+        Secrets { resolvers: vec![Box::new(Fixed)] }
+    }
+
+    #[test]
+    fn interpolates_references() {
+        let secrets = secrets();
+        assert_eq!(secrets.interpolate("plain").unwrap(), "plain");
+        assert_eq!(secrets.interpolate("${fixed:token}").unwrap(), "s3cr3t");
+        assert_eq!(
+            secrets.interpolate("https://discord.com/api/webhooks/1/${fixed:token}?wait=true").unwrap(),
+            "https://discord.com/api/webhooks/1/s3cr3t?wait=true"
+        );
+        assert_eq!(secrets.interpolate("${fixed:token}${fixed:token}").unwrap(), "s3cr3ts3cr3t");
+    }
+
+    #[test]
+    fn keeps_escaped_references() {
+        let secrets = secrets();
+        assert_eq!(secrets.interpolate("$${fixed:token}").unwrap(), "${fixed:token}");
+        assert_eq!(secrets.interpolate("a$${b} ${fixed:token}").unwrap(), "a${b} s3cr3t");
+    }
+
+    #[test]
+    fn fails_on_bad_references() {
+        let secrets = secrets();
+        assert!(secrets.interpolate("${fixed:token").is_err());
+        assert!(secrets.interpolate("${fixed:other}").is_err());
+        // Without a scheme the reference is an environment variable, and no env resolver is registered here
+        let error = secrets.interpolate("${TOKEN}").err().unwrap();
+        assert!(error.to_string().contains("${env:...}"), "{}", error);
+    }
+
+    #[test]
+    fn redacts_webhook_tokens() {
+        assert_eq!(
+            redact("https://discord.com/api/webhooks/123/abcdef"),
+            "https://discord.com/api/webhooks/123/***"
+        );
+        assert_eq!(redact("http://127.0.0.1:8080/webhooks/123/abcdef"), "http://127.0.0.1:8080/webhooks/123/***");
+        assert_eq!(redact("alaydriem.com"), "alaydriem.com");
+    }
+}
@@ -0,0 +1,225 @@
+use anyhow::anyhow;
+use reqwest::redirect::{ Attempt, Policy };
+use reqwest::Url;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::net::IpAddr;
+
+/// Base URLs of everything the relay talks to, so staging and tests can point it at local stand-ins
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ServicesConfig {
+    /// Playlist feeds, fetched as `<feeds>?playlist_id=<id>`
+    #[serde(default = "ServicesConfig::default_feeds")]
+    pub feeds: String,
+    /// Video thumbnails, fetched as `<thumbnails>/<id>/maxresdefault.jpg`
+    #[serde(default = "ServicesConfig::default_thumbnails")]
+    pub thumbnails: String,
+    /// The links announcements point at, `<watch>?v=<id>`
+    #[serde(default = "ServicesConfig::default_watch")]
+    pub watch: String,
+    /// Asked whether a video is still available
+    #[serde(default = "ServicesConfig::default_oembed")]
+    pub oembed: String,
+    /// The YouTube Data API, which lists every video of a playlist when `fetch.api_key` is set
+    #[serde(default = "ServicesConfig::default_youtube_api")]
+    pub youtube_api: String,
+    /// The Bluesky PDS accounts log in to
+    #[serde(default = "ServicesConfig::default_bluesky")]
+    pub bluesky: String,
+    /// The Discord API used with a bot_token
+    #[serde(default = "ServicesConfig::default_discord_api")]
+    pub discord_api: String,
+    /// Where WebSub subscriptions are sent
+    #[serde(default = "ServicesConfig::default_websub_hub")]
+    pub websub_hub: String,
+    /// Allow plain HTTP to localhost and loopback addresses. Everything else is always HTTPS
+    #[serde(default)]
+    pub allow_http_loopback: bool,
+}
+
+impl Default for ServicesConfig {
+    fn default() -> Self {
+        Self {
+            feeds: Self::default_feeds(),
+            thumbnails: Self::default_thumbnails(),
+            watch: Self::default_watch(),
+            oembed: Self::default_oembed(),
+            youtube_api: Self::default_youtube_api(),
+            bluesky: Self::default_bluesky(),
+            discord_api: Self::default_discord_api(),
+            websub_hub: Self::default_websub_hub(),
+            allow_http_loopback: false,
+        }
+    }
+}
+
+impl ServicesConfig {
+    fn default_feeds() -> String {
+        "https://www.youtube.com/feeds/videos.xml".to_string()
+    }
+
+    fn default_thumbnails() -> String {
+        "https://img.youtube.com/vi".to_string()
+    }
+
+    fn default_watch() -> String {
+        "https://www.youtube.com/watch".to_string()
+    }
+
+    fn default_oembed() -> String {
+        "https://www.youtube.com/oembed".to_string()
+    }
+
+    fn default_youtube_api() -> String {
+        "https://www.googleapis.com/youtube/v3".to_string()
+    }
+
+    fn default_bluesky() -> String {
+        "https://bsky.social".to_string()
+    }
+
+    fn default_discord_api() -> String {
+        "https://discord.com/api/v10".to_string()
+    }
+
+    fn default_websub_hub() -> String {
+        "https://pubsubhubbub.appspot.com/subscribe".to_string()
+    }
+
+    pub fn feed(&self, playlist: &str) -> String {
+        format!("{}?playlist_id={}", self.feeds, playlist)
+    }
+
+    pub fn thumbnail(&self, video: &str) -> String {
+        format!("{}/{}/maxresdefault.jpg", self.thumbnails.trim_end_matches('/'), video)
+    }
+
+    pub fn watch(&self, video: &str) -> String {
+        format!("{}?v={}", self.watch, video)
+    }
+
+    /// Every base URL, by field name
+    pub fn urls(&self) -> [(&'static str, &str); 8] {
+        [
+            ("feeds", &self.feeds),
+            ("thumbnails", &self.thumbnails),
+            ("watch", &self.watch),
+            ("oembed", &self.oembed),
+            ("youtube_api", &self.youtube_api),
+            ("bluesky", &self.bluesky),
+            ("discord_api", &self.discord_api),
+            ("websub_hub", &self.websub_hub),
+        ]
+    }
+
+    /// Whether the relay may request `url`: HTTPS, or plain HTTP to a loopback host when allowed.
+    /// The error leaves the URL out
+    pub fn check_url(&self, url: &str) -> anyhow::Result<()> {
+        let url = Url::parse(url).map_err(|e| anyhow!("not a valid URL: {}", e))?;
+        if is_allowed(&url, self.allow_http_loopback) {
+            Ok(())
+        } else {
+            Err(anyhow!("must use https"))
+        }
+    }
+
+    /// Keeps redirects to the same rules as the URLs they start from, and never from HTTPS back to HTTP
+    pub fn redirect_policy(&self) -> Policy {
+        let allow_http_loopback = self.allow_http_loopback;
+        Policy::custom(move |attempt: Attempt| {
+            if attempt.previous().len() >= 10 {
+                attempt.error("too many redirects")
+            } else if !may_follow(attempt.previous(), attempt.url(), allow_http_loopback) {
+                let error = format!("refusing to follow a redirect to {}", attempt.url());
+                attempt.error(error)
+            } else {
+                attempt.follow()
+            }
+        })
+    }
+}
+
+/// Whether a redirect through `previous` may go on to `url`
+fn may_follow(previous: &[Url], url: &Url, allow_http_loopback: bool) -> bool {
+    let downgrade = url.scheme() == "http" && previous.iter().any(|from| from.scheme() == "https");
+    !downgrade && is_allowed(url, allow_http_loopback)
+}
+
+fn is_allowed(url: &Url, allow_http_loopback: bool) -> bool {
+    match url.scheme() {
+        "https" => true,
+        "http" => allow_http_loopback && url.host_str().is_some_and(is_loopback),
+        _ => false,
+    }
+}
+
+fn is_loopback(host: &str) -> bool {
+    host == "localhost" ||
+        host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse::<IpAddr>()
+            .is_ok_and(|ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::response::Redirect;
+    use axum::routing::get;
+    use axum::Router;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    #[test]
+    fn recognizes_loopback_hosts() {
+        assert!(is_loopback("localhost"));
+        assert!(is_loopback("127.0.0.1"));
+        assert!(is_loopback("[::1]"));
+        assert!(!is_loopback("example.com"));
+        assert!(!is_loopback("192.0.2.1"));
+    }
+
+    #[test]
+    fn only_allows_http_to_loopback_hosts_when_asked() {
+        for loopback in ["http://localhost:8080/feeds", "http://127.0.0.1/feeds", "http://[::1]:8080/feeds"] {
+            assert!(is_allowed(&url(loopback), true), "{}", loopback);
+            assert!(!is_allowed(&url(loopback), false), "{}", loopback);
+        }
+        assert!(!is_allowed(&url("http://example.com/feeds"), true));
+        assert!(is_allowed(&url("https://example.com/feeds"), false));
+        assert!(!is_allowed(&url("ftp://localhost/feeds"), true));
+    }
+
+    #[test]
+    fn never_follows_https_back_to_http() {
+        let https = [url("https://example.com/feeds")];
+        let http = [url("http://localhost/feeds")];
+        assert!(may_follow(&https, &url("https://example.org/feeds"), true));
+        assert!(!may_follow(&https, &url("http://localhost/moved"), true));
+        assert!(may_follow(&http, &url("http://127.0.0.1/moved"), true));
+        assert!(may_follow(&http, &url("https://example.com/moved"), true));
+        assert!(!may_follow(&http, &url("http://example.com/moved"), true));
+    }
+
+    #[tokio::test]
+    async fn refuses_redirects_to_http_elsewhere() {
+        let app = Router::new()
+            .route("/elsewhere", get(|| async { Redirect::temporary("http://192.0.2.1/feeds") }))
+            .route("/here", get(|| async { Redirect::temporary("/feeds") }))
+            .route("/feeds", get(|| async { "feed" }));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let services = ServicesConfig { allow_http_loopback: true, ..Default::default() };
+        let client = reqwest::Client::builder().redirect(services.redirect_policy()).build().unwrap();
+        let followed = client.get(format!("http://{}/here", address)).send().await.unwrap();
+        assert_eq!(followed.text().await.unwrap(), "feed");
+        let refused = client.get(format!("http://{}/elsewhere", address)).send().await.unwrap_err();
+        assert!(refused.is_redirect());
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::OnceLock;
+use tokio::sync::watch;
+
+fn requests() -> &'static watch::Sender<bool> {
+    static REQUESTS: OnceLock<watch::Sender<bool>> = OnceLock::new();
+    REQUESTS.get_or_init(|| watch::channel(false).0)
+}
+
+/// Turns SIGINT and SIGTERM into a shutdown request, so the delivery in flight finishes and
+/// its result is recorded before the process exits. A second signal exits right away
+pub fn listen() {
+    tokio::spawn(async {
+        signal().await;
+        tracing::info!("Shutting down once the delivery in flight is recorded");
+        request();
+
+        signal().await;
+        tracing::warn!("Exiting without waiting for the delivery in flight");
+        std::process::exit(130);
+    });
+}
+
+/// Asks for a shutdown, as SIGINT and SIGTERM do
+pub fn request() {
+    requests().send_replace(true);
+}
+
+/// Whether a shutdown was requested. Long loops check this between two deliveries
+pub fn requested() -> bool {
+    *requests().borrow()
+}
+
+/// Resolves once a shutdown is requested
+pub async fn wait() {
+    let mut requests = requests().subscribe();
+    let _ = requests.wait_for(|requested| *requested).await;
+}
+
+#[cfg(unix)]
+async fn signal() {
+    use tokio::signal::unix::{ self, SignalKind };
+
+    let mut terminate = match unix::signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            tracing::error!("Unable to listen for SIGTERM: {:?}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate.recv() => {}
+    }
+}
+
+#[cfg(not(unix))]
+async fn signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
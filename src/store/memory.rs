@@ -0,0 +1,538 @@
+use async_trait::async_trait;
+use chrono::Utc;
+use std::collections::{ HashMap, HashSet };
+use std::sync::{ Mutex, MutexGuard };
+
+use super::{ is_changed, video_id, FeedCache, Pause, PauseScope, PollStatus, Upsert, Video, VideoStore };
+use crate::data::Entry;
+use crate::delivery::{ Delivery, DeliveryKey, DeliveryStatus, Receipt };
+use crate::WebhookType;
+use crate::retry::RetryPolicy;
+
+#[derive(Debug, Default)]
+struct State {
+    videos: Vec<Video>,
+    playlists: HashSet<String>,
+    /// Videos found to have been taken down from YouTube
+    removed: HashSet<String>,
+    /// Videos taken out of a playlist, as (playlist, video)
+    left: HashSet<(String, String)>,
+    deliveries: Vec<Delivery>,
+    feeds: HashMap<String, FeedCache>,
+    pauses: Vec<Pause>,
+    polls: HashMap<String, PollStatus>,
+}
+
+/// Timestamps are kept in the same format SQLite's CURRENT_TIMESTAMP uses
+fn sql_timestamp(timestamp: chrono::DateTime<Utc>) -> String {
+    timestamp.format("%Y-%m-%d %H:%M:%S").to_string()
+}
+
+fn matches(delivery: &Delivery, key: &DeliveryKey) -> bool {
+    delivery.video == key.video &&
+        delivery.playlist == key.playlist &&
+        delivery.destination == key.destination.as_str() &&
+        delivery.target == key.target
+}
+
+/// Keeps everything in process memory. Nothing survives a restart, so this is only meant for tests
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    state: Mutex<State>,
+}
+
+impl MemoryStore {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().expect("memory store lock poisoned")
+    }
+}
+
+#[async_trait]
+impl VideoStore for MemoryStore {
+    async fn upsert_entry(&self, playlist: &str, entry: &Entry, hooked: bool) -> anyhow::Result<Upsert> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        let id = video_id(entry);
+        let previous = state.videos
+            .iter()
+            .find(|v| v.id == id)
+            .map(|v| (v.title.clone(), v.updated.clone()));
+
+        // Each playlist a video is in gets its own row
+        let linked = !state.videos.iter().any(|v| v.id == id && v.playlist == playlist);
+        if linked {
+            state.videos.push(Video {
+                id: id.clone(),
+                playlist: playlist.to_string(),
+                title: entry.title.clone(),
+                author: entry.author.as_ref().map_or(String::new(), |author| author.name.clone()),
+                timestamp: entry.published.clone(),
+                hooked: hooked as i32,
+                updated: entry.updated.clone(),
+            });
+        }
+        let unchanged = if linked { Upsert::Inserted } else { Upsert::Unchanged };
+
+        let (title, updated) = match previous {
+            Some(previous) => previous,
+            None => {
+                return Ok(Upsert::Inserted);
+            }
+        };
+
+        for v in state.videos.iter_mut().filter(|v| v.id == id) {
+            v.title = entry.title.clone();
+            v.updated = entry.updated.clone();
+        }
+        if !is_changed(&title, &updated, entry) {
+            return Ok(unchanged);
+        }
+
+        for d in state.deliveries.iter_mut() {
+            if
+                d.video == id &&
+                d.destination == WebhookType::Discord.as_str() &&
+                d.status == DeliveryStatus::Delivered.as_str() &&
+                d.message_id.is_some()
+            {
+                d.edit_pending = true;
+            }
+        }
+
+        Ok(Upsert::Changed)
+    }
+
+    async fn mark_hooked(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        let mut state = self.state();
+        for v in state.videos.iter_mut().filter(|v| v.id == video && v.playlist == playlist) {
+            v.hooked = 1;
+        }
+        Ok(())
+    }
+
+    async fn unhooked_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<String>> {
+        Ok(
+            self
+                .state()
+                .videos.iter()
+                .filter(|v| v.hooked == 0 && v.playlist == playlist)
+                .map(|v| v.id.clone())
+                .collect()
+        )
+    }
+
+    async fn pending_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let state = self.state();
+        let mut videos: Vec<Video> = state.videos
+            .iter()
+            .filter(|v| v.playlist == playlist)
+            .filter(|v| {
+                state.deliveries
+                    .iter()
+                    .any(|d| {
+                        d.video == v.id &&
+                            d.playlist == v.playlist &&
+                            d.status == DeliveryStatus::Pending.as_str()
+                    })
+            })
+            .cloned()
+            .collect();
+        videos.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
+
+        Ok(videos)
+    }
+
+    async fn is_known_playlist(&self, playlist: &str) -> anyhow::Result<bool> {
+        let state = self.state();
+        Ok(state.playlists.contains(playlist) || state.videos.iter().any(|v| v.playlist == playlist))
+    }
+
+    async fn mark_known_playlist(&self, playlist: &str) -> anyhow::Result<()> {
+        self.state().playlists.insert(playlist.to_string());
+        Ok(())
+    }
+
+    async fn enqueue(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        let mut state = self.state();
+        if !state.deliveries.iter().any(|d| matches(d, key)) {
+            state.deliveries.push(Delivery {
+                video: key.video.to_string(),
+                playlist: key.playlist.to_string(),
+                destination: key.destination.as_str().to_string(),
+                target: key.target.to_string(),
+                status: DeliveryStatus::Pending.as_str().to_string(),
+                attempts: 0,
+                last_error: None,
+                next_attempt_at: None,
+                updated_at: sql_timestamp(Utc::now()),
+                message_id: None,
+                thread_id: None,
+                record_cid: None,
+                edit_pending: false,
+            });
+        }
+        Ok(())
+    }
+
+    async fn is_due(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let now = sql_timestamp(Utc::now());
+        Ok(
+            self
+                .state()
+                .deliveries.iter()
+                .find(|d| matches(d, key))
+                .is_some_and(|d| {
+                    d.status == DeliveryStatus::Pending.as_str() &&
+                        d.next_attempt_at.as_ref().is_none_or(|next| *next <= now)
+                })
+        )
+    }
+
+    async fn mark_delivered(&self, key: &DeliveryKey<'_>, receipt: &Receipt) -> anyhow::Result<()> {
+        for d in self.state().deliveries.iter_mut().filter(|d| matches(d, key)) {
+            d.status = DeliveryStatus::Delivered.as_str().to_string();
+            d.attempts += 1;
+            d.last_error = None;
+            d.next_attempt_at = None;
+            d.message_id = receipt.message_id.clone();
+            d.thread_id = receipt.thread_id.clone();
+            d.record_cid = receipt.record_cid.clone();
+            d.updated_at = sql_timestamp(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        key: &DeliveryKey<'_>,
+        error: &str,
+        policy: &RetryPolicy
+    ) -> anyhow::Result<DeliveryStatus> {
+        let mut state = self.state();
+        let delivery = match state.deliveries.iter_mut().find(|d| matches(d, key)) {
+            Some(delivery) => delivery,
+            None => {
+                return Ok(DeliveryStatus::Pending);
+            }
+        };
+
+        delivery.attempts += 1;
+        let status = if policy.is_exhausted(delivery.attempts) {
+            DeliveryStatus::Dead
+        } else {
+            DeliveryStatus::Pending
+        };
+        let backoff = chrono::Duration::from_std(policy.backoff(delivery.attempts))?;
+
+        delivery.status = status.as_str().to_string();
+        delivery.last_error = Some(error.to_string());
+        delivery.next_attempt_at = Some(sql_timestamp(Utc::now() + backoff));
+        delivery.updated_at = sql_timestamp(Utc::now());
+
+        Ok(status)
+    }
+
+    async fn skip_pending(&self, video: &str, playlist: &str) -> anyhow::Result<usize> {
+        let mut skipped = 0;
+        for d in self.state().deliveries.iter_mut() {
+            if
+                d.video == video &&
+                d.playlist == playlist &&
+                (d.status == DeliveryStatus::Pending.as_str() ||
+                    d.status == DeliveryStatus::Dead.as_str())
+            {
+                d.status = DeliveryStatus::Skipped.as_str().to_string();
+                d.next_attempt_at = None;
+                d.updated_at = sql_timestamp(Utc::now());
+                skipped += 1;
+            }
+        }
+        Ok(skipped)
+    }
+
+    async fn has_delivery(
+        &self,
+        video: &str,
+        destination: &WebhookType,
+        target: &str
+    ) -> anyhow::Result<bool> {
+        Ok(
+            self
+                .state()
+                .deliveries.iter()
+                .any(|d| {
+                    d.video == video && d.destination == destination.as_str() && d.target == target
+                })
+        )
+    }
+
+    async fn pending_count(&self, video: &str, playlist: &str) -> anyhow::Result<i64> {
+        Ok(
+            self
+                .state()
+                .deliveries.iter()
+                .filter(|d| {
+                    d.video == video &&
+                        d.playlist == playlist &&
+                        d.status == DeliveryStatus::Pending.as_str()
+                })
+                .count() as i64
+        )
+    }
+
+    async fn pending_edits(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let state = self.state();
+        Ok(
+            state.deliveries
+                .iter()
+                .filter(|d| d.playlist == playlist && d.edit_pending)
+                .filter_map(|d| {
+                    state.videos
+                        .iter()
+                        .find(|v| v.id == d.video && v.playlist == d.playlist)
+                        .map(|v| (v.clone(), d.clone()))
+                })
+                .collect()
+        )
+    }
+
+    async fn mark_edited(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        for d in self.state().deliveries.iter_mut().filter(|d| matches(d, key)) {
+            d.edit_pending = false;
+            d.updated_at = sql_timestamp(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn announced_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let state = self.state();
+        Ok(
+            state.videos
+                .iter()
+                .filter(|v| v.playlist == playlist && !state.removed.contains(&v.id))
+                .filter(|v| !state.left.contains(&(v.playlist.clone(), v.id.clone())))
+                .filter(|v| {
+                    state.deliveries
+                        .iter()
+                        .any(|d| {
+                            d.video == v.id &&
+                                d.playlist == playlist &&
+                                d.status == DeliveryStatus::Delivered.as_str()
+                        })
+                })
+                .cloned()
+                .collect()
+        )
+    }
+
+    async fn mark_removed(&self, video: &str) -> anyhow::Result<()> {
+        let mut state = self.state();
+        state.removed.insert(video.to_string());
+        for d in state.deliveries.iter_mut() {
+            if
+                d.video == video &&
+                (d.status == DeliveryStatus::Pending.as_str() ||
+                    d.status == DeliveryStatus::Dead.as_str())
+            {
+                d.status = DeliveryStatus::Skipped.as_str().to_string();
+                d.next_attempt_at = None;
+                d.updated_at = sql_timestamp(Utc::now());
+            }
+        }
+        Ok(())
+    }
+
+    async fn mark_removed_from(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        let mut state = self.state();
+        state.left.insert((playlist.to_string(), video.to_string()));
+        for d in state.deliveries.iter_mut() {
+            if
+                d.video == video &&
+                d.playlist == playlist &&
+                (d.status == DeliveryStatus::Pending.as_str() ||
+                    d.status == DeliveryStatus::Dead.as_str())
+            {
+                d.status = DeliveryStatus::Skipped.as_str().to_string();
+                d.next_attempt_at = None;
+                d.updated_at = sql_timestamp(Utc::now());
+            }
+        }
+        Ok(())
+    }
+
+    async fn pending_retractions(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let state = self.state();
+        Ok(
+            state.deliveries
+                .iter()
+                .filter(|d| {
+                    d.playlist == playlist &&
+                        d.status == DeliveryStatus::Delivered.as_str() &&
+                        (state.removed.contains(&d.video) ||
+                            state.left.contains(&(d.playlist.clone(), d.video.clone())))
+                })
+                .filter_map(|d| {
+                    state.videos
+                        .iter()
+                        .find(|v| v.id == d.video && v.playlist == d.playlist)
+                        .map(|v| (v.clone(), d.clone()))
+                })
+                .collect()
+        )
+    }
+
+    async fn mark_retracted(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        for d in self.state().deliveries.iter_mut().filter(|d| matches(d, key)) {
+            d.status = DeliveryStatus::Retracted.as_str().to_string();
+            d.edit_pending = false;
+            d.updated_at = sql_timestamp(Utc::now());
+        }
+        Ok(())
+    }
+
+    async fn videos(&self, playlist: Option<&str>) -> anyhow::Result<Vec<Video>> {
+        let mut videos: Vec<Video> = self
+            .state()
+            .videos.iter()
+            .filter(|v| playlist.is_none_or(|playlist| v.playlist == playlist))
+            .cloned()
+            .collect();
+        videos.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+
+        Ok(videos)
+    }
+
+    async fn deliveries(&self, video: &str) -> anyhow::Result<Vec<Delivery>> {
+        let mut deliveries: Vec<Delivery> = self
+            .state()
+            .deliveries.iter()
+            .filter(|d| d.video == video)
+            .cloned()
+            .collect();
+        deliveries.sort_by(|a, b| {
+            (&a.playlist, &a.destination, &a.target).cmp(&(&b.playlist, &b.destination, &b.target))
+        });
+
+        Ok(deliveries)
+    }
+
+    async fn replay(&self, video: &str, destination: Option<&str>) -> anyhow::Result<usize> {
+        let mut replayed = 0;
+        for d in self.state().deliveries.iter_mut() {
+            if d.video == video && destination.is_none_or(|destination| d.destination == destination) {
+                d.status = DeliveryStatus::Pending.as_str().to_string();
+                d.attempts = 0;
+                d.last_error = None;
+                d.next_attempt_at = None;
+                d.edit_pending = false;
+                d.updated_at = sql_timestamp(Utc::now());
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+
+    async fn feed_cache(&self, url: &str) -> anyhow::Result<Option<FeedCache>> {
+        Ok(self.state().feeds.get(url).cloned())
+    }
+
+    async fn save_feed_cache(&self, cache: &FeedCache) -> anyhow::Result<()> {
+        self.state().feeds.insert(cache.url.clone(), cache.clone());
+        Ok(())
+    }
+
+    async fn requeue_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let mut state = self.state();
+        match state.deliveries.iter_mut().find(|d| matches(d, key)) {
+            Some(d) => {
+                d.status = DeliveryStatus::Pending.as_str().to_string();
+                d.attempts = 0;
+                d.last_error = None;
+                d.next_attempt_at = None;
+                d.edit_pending = false;
+                d.updated_at = sql_timestamp(Utc::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn skip_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let mut state = self.state();
+        let outstanding = state.deliveries.iter_mut().find(|d| {
+            matches(d, key) &&
+                (d.status == DeliveryStatus::Pending.as_str() || d.status == DeliveryStatus::Dead.as_str())
+        });
+        match outstanding {
+            Some(d) => {
+                d.status = DeliveryStatus::Skipped.as_str().to_string();
+                d.next_attempt_at = None;
+                d.updated_at = sql_timestamp(Utc::now());
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    async fn pauses(&self) -> anyhow::Result<Vec<Pause>> {
+        let mut pauses = self.state().pauses.to_vec();
+        pauses.sort_by(|a, b| (&a.scope, &a.name).cmp(&(&b.scope, &b.name)));
+        Ok(pauses)
+    }
+
+    async fn set_paused(&self, scope: PauseScope, name: &str, paused: bool) -> anyhow::Result<()> {
+        let pause = Pause { scope: scope.as_str().to_string(), name: name.to_string() };
+        let mut state = self.state();
+        state.pauses.retain(|p| *p != pause);
+        if paused {
+            state.pauses.push(pause);
+        }
+        Ok(())
+    }
+
+    async fn record_poll(&self, playlist: &str, status: &str) -> anyhow::Result<()> {
+        self.state().polls.insert(playlist.to_string(), PollStatus {
+            playlist: playlist.to_string(),
+            polled_at: sql_timestamp(Utc::now()),
+            status: status.to_string(),
+        });
+        Ok(())
+    }
+
+    async fn polls(&self) -> anyhow::Result<Vec<PollStatus>> {
+        let mut polls: Vec<PollStatus> = self.state().polls.values().cloned().collect();
+        polls.sort_by(|a, b| a.playlist.cmp(&b.playlist));
+        Ok(polls)
+    }
+
+    async fn dead_letters(&self) -> anyhow::Result<Vec<Delivery>> {
+        let mut deliveries: Vec<Delivery> = self
+            .state()
+            .deliveries.iter()
+            .filter(|d| d.status == DeliveryStatus::Dead.as_str())
+            .cloned()
+            .collect();
+        deliveries.sort_by(|a, b| a.updated_at.cmp(&b.updated_at));
+
+        Ok(deliveries)
+    }
+
+    async fn requeue(&self, video: Option<&str>, destination: Option<&str>) -> anyhow::Result<usize> {
+        let mut requeued = 0;
+        for d in self.state().deliveries.iter_mut() {
+            if
+                d.status == DeliveryStatus::Dead.as_str() &&
+                video.is_none_or(|video| d.video == video) &&
+                destination.is_none_or(|destination| d.destination == destination)
+            {
+                d.status = DeliveryStatus::Pending.as_str().to_string();
+                d.attempts = 0;
+                d.last_error = None;
+                d.next_attempt_at = None;
+                d.updated_at = sql_timestamp(Utc::now());
+                requeued += 1;
+            }
+        }
+        Ok(requeued)
+    }
+}
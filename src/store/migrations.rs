@@ -0,0 +1,250 @@
+use anyhow::anyhow;
+use rusqlite::Connection;
+
+/// A single schema change. Migrations are applied in order, each in its own transaction,
+/// and the database's `PRAGMA user_version` records the last one applied
+struct Migration {
+    version: u32,
+    name: &'static str,
+    up: fn(&Connection) -> rusqlite::Result<()>,
+}
+
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "baseline",
+        up: baseline,
+    },
+    Migration {
+        version: 2,
+        name: "video timestamp is no longer unique",
+        up: non_unique_timestamp,
+    },
+    Migration {
+        version: 3,
+        name: "track video updates and delivered message ids",
+        up: message_ids,
+    },
+    Migration {
+        version: 4,
+        name: "track removed videos",
+        up: removed_videos,
+    },
+    Migration {
+        version: 5,
+        name: "videos can belong to several playlists",
+        up: playlist_videos,
+    },
+    Migration {
+        version: 6,
+        name: "cache feed responses",
+        up: feed_cache,
+    },
+    Migration {
+        version: 7,
+        name: "pauses and poll status",
+        up: admin,
+    },
+    Migration {
+        version: 8,
+        name: "track videos taken out of a playlist",
+        up: removed_from_playlist,
+    },
+];
+
+/// Fails unless the database is at the latest schema version, without changing it
+pub fn check(connection: &Connection) -> anyhow::Result<()> {
+    let current: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current != latest {
+        return Err(
+            anyhow!(
+                "The database is at schema version {}, but this relay expects {}. Run it without --dry-run to migrate",
+                current,
+                latest
+            )
+        );
+    }
+
+    Ok(())
+}
+
+/// Brings the database up to the latest schema version
+pub fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let current: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    let latest = MIGRATIONS.last().map_or(0, |m| m.version);
+    if current > latest {
+        return Err(
+            anyhow!(
+                "The database is at schema version {}, but this relay only knows up to {}",
+                current,
+                latest
+            )
+        );
+    }
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let tx = connection.transaction()?;
+        (migration.up)(&tx)?;
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+        tracing::info!("Applied migration {}: {}", migration.version, migration.name);
+    }
+
+    Ok(())
+}
+
+/// The tables as they existed before migrations were tracked.
+/// Every statement tolerates a database that already has them
+fn baseline(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE IF NOT EXISTS video (id VARCHAR(255) PRIMARY KEY, playlist VARCHAR(255), title VARCHAR(255), author VARCHAR(255), timestamp DATETIME UNIQUE, hooked BOOLEAN DEFAULT 0);
+        CREATE TABLE IF NOT EXISTS playlist (id VARCHAR(255) PRIMARY KEY, seen_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP);
+        CREATE TABLE IF NOT EXISTS delivery (video VARCHAR(255) NOT NULL, playlist VARCHAR(255) NOT NULL, destination VARCHAR(32) NOT NULL, target VARCHAR(255) NOT NULL, status VARCHAR(16) NOT NULL DEFAULT 'pending', attempts INTEGER NOT NULL DEFAULT 0, last_error TEXT, next_attempt_at DATETIME, created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, updated_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, delivered_at DATETIME, PRIMARY KEY (video, playlist, destination, target));"
+    )?;
+
+    // Ledgers created before retries were scheduled don't have this column yet
+    let has_next_attempt_at: bool = connection.query_row(
+        "SELECT COUNT(*) > 0 FROM pragma_table_info('delivery') WHERE name = 'next_attempt_at'",
+        (),
+        |row| row.get(0)
+    )?;
+    if !has_next_attempt_at {
+        connection.execute("ALTER TABLE delivery ADD COLUMN next_attempt_at DATETIME", ())?;
+    }
+
+    Ok(())
+}
+
+/// `timestamp DATETIME UNIQUE` made `INSERT OR IGNORE` drop any video published in the
+/// same second as another one. SQLite can't drop a constraint, so the table is rebuilt
+fn non_unique_timestamp(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE video_v2 (id VARCHAR(255) PRIMARY KEY, playlist VARCHAR(255), title VARCHAR(255), author VARCHAR(255) NOT NULL DEFAULT '', timestamp DATETIME, hooked BOOLEAN DEFAULT 0);
+        INSERT INTO video_v2 (id, playlist, title, author, timestamp, hooked) SELECT id, playlist, title, COALESCE(author, ''), timestamp, hooked FROM video;
+        DROP TABLE video;
+        ALTER TABLE video_v2 RENAME TO video;
+        CREATE INDEX video_playlist_timestamp ON video (playlist, timestamp);"
+    )
+}
+
+/// Keeps the `updated` timestamp of each video and the message each delivery created,
+/// so announcements can be edited when a video changes
+fn message_ids(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "ALTER TABLE video ADD COLUMN updated VARCHAR(255) NOT NULL DEFAULT '';
+        ALTER TABLE delivery ADD COLUMN message_id VARCHAR(255);
+        ALTER TABLE delivery ADD COLUMN thread_id VARCHAR(255);
+        ALTER TABLE delivery ADD COLUMN edit_pending BOOLEAN NOT NULL DEFAULT 0;"
+    )
+}
+
+/// Flags videos taken down from YouTube, and keeps the Bluesky CID needed to reply to a post
+fn removed_videos(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "ALTER TABLE video ADD COLUMN removed BOOLEAN NOT NULL DEFAULT 0;
+        ALTER TABLE delivery ADD COLUMN record_cid VARCHAR(255);"
+    )
+}
+
+/// Moves the playlist a video belongs to, and whether it was hooked there, into `playlist_video`
+/// so one video can be announced for every playlist it is in
+fn playlist_videos(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE playlist_video (playlist VARCHAR(255) NOT NULL, video VARCHAR(255) NOT NULL, hooked INTEGER NOT NULL DEFAULT 0, added_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, PRIMARY KEY (playlist, video));
+        INSERT INTO playlist_video (playlist, video, hooked) SELECT playlist, id, COALESCE(hooked, 0) FROM video WHERE playlist IS NOT NULL;
+        CREATE INDEX playlist_video_video ON playlist_video (video);
+        CREATE TABLE video_v5 (id VARCHAR(255) PRIMARY KEY, title VARCHAR(255), author VARCHAR(255) NOT NULL DEFAULT '', timestamp DATETIME, updated VARCHAR(255) NOT NULL DEFAULT '', removed BOOLEAN NOT NULL DEFAULT 0);
+        INSERT INTO video_v5 (id, title, author, timestamp, updated, removed) SELECT id, title, author, timestamp, updated, removed FROM video;
+        DROP TABLE video;
+        ALTER TABLE video_v5 RENAME TO video;
+        CREATE INDEX video_timestamp ON video (timestamp);"
+    )
+}
+
+/// Keeps the validators and body hash of each feed, so unchanged feeds aren't parsed again
+fn feed_cache(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE feed_cache (url VARCHAR(1024) PRIMARY KEY, etag VARCHAR(255), last_modified VARCHAR(255), hash VARCHAR(64) NOT NULL, fetched_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP);"
+    )
+}
+
+/// Keeps what was paused through the admin API, and how each playlist's last poll went
+fn admin(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch(
+        "CREATE TABLE pause (scope VARCHAR(32) NOT NULL, name VARCHAR(255) NOT NULL, paused_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, PRIMARY KEY (scope, name));
+        CREATE TABLE poll (playlist VARCHAR(255) PRIMARY KEY, polled_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, status VARCHAR(16) NOT NULL);"
+    )
+}
+
+/// Flags a video that is still on YouTube but no longer in one of its playlists
+fn removed_from_playlist(connection: &Connection) -> rusqlite::Result<()> {
+    connection.execute_batch("ALTER TABLE playlist_video ADD COLUMN removed BOOLEAN NOT NULL DEFAULT 0;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrades_an_untracked_database_in_place() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.execute_batch(
+            "CREATE TABLE video (id VARCHAR(255) PRIMARY KEY, playlist VARCHAR(255), title VARCHAR(255), author VARCHAR(255), timestamp DATETIME UNIQUE, hooked BOOLEAN DEFAULT 0);
+            INSERT INTO video VALUES ('a', 'PL1', 'First', 'Someone', '2024-01-01T00:00:00+00:00', 1);
+            INSERT INTO video VALUES ('b', 'PL1', 'Second', NULL, '2024-01-02T00:00:00+00:00', 0);"
+        ).unwrap();
+
+        migrate(&mut connection).unwrap();
+
+        let version: u32 = connection.pragma_query_value(None, "user_version", |row| row.get(0)).unwrap();
+        assert_eq!(version, MIGRATIONS.last().unwrap().version);
+
+        let videos = connection
+            .prepare(
+                "SELECT video.id, video.author, playlist_video.playlist, playlist_video.hooked FROM video JOIN playlist_video ON playlist_video.video = video.id ORDER BY video.id"
+            )
+            .unwrap()
+            .query_map((), |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(String, String, String, i32)>>>()
+            .unwrap();
+        assert_eq!(
+            videos,
+            vec![
+                ("a".to_string(), "Someone".to_string(), "PL1".to_string(), 1),
+                ("b".to_string(), String::new(), "PL1".to_string(), 0)
+            ]
+        );
+
+        // Two videos published in the same second no longer collide
+        connection
+            .execute(
+                "INSERT INTO video (id, title, timestamp) VALUES ('c', 'Third', '2024-01-02T00:00:00+00:00')",
+                ()
+            )
+            .unwrap();
+    }
+
+    #[test]
+    fn applies_nothing_twice() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        migrate(&mut connection).unwrap();
+        migrate(&mut connection).unwrap();
+    }
+
+    #[test]
+    fn checks_without_migrating() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        assert!(check(&connection).is_err());
+        migrate(&mut connection).unwrap();
+        check(&connection).unwrap();
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut connection = Connection::open_in_memory().unwrap();
+        connection.pragma_update(None, "user_version", MIGRATIONS.last().unwrap().version + 1).unwrap();
+        assert!(migrate(&mut connection).is_err());
+    }
+}
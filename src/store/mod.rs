@@ -0,0 +1,431 @@
+use async_trait::async_trait;
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::data::Entry;
+use crate::delivery::{ Delivery, DeliveryKey, DeliveryStatus, Receipt };
+use crate::retry::RetryPolicy;
+use crate::WebhookType;
+
+mod memory;
+mod migrations;
+mod postgres;
+mod sqlite;
+
+pub use memory::MemoryStore;
+pub use postgres::PostgresStore;
+pub use sqlite::SqliteStore;
+
+/// A video as seen from one of the playlists it is in.
+/// `playlist` and `hooked` belong to that membership, everything else to the video itself
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Video {
+    pub id: String,
+    pub playlist: String,
+    pub title: String,
+    pub author: String,
+    pub timestamp: String,
+    pub hooked: i32,
+    pub updated: String,
+}
+
+/// What the relay remembers of the last response for a feed URL, so it can be fetched conditionally
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FeedCache {
+    pub url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    /// SHA-256 of the body, in hex
+    pub hash: String,
+}
+
+/// What can be paused through the admin API
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum PauseScope {
+    /// A playlist, by id. It isn't polled while paused
+    #[serde(rename = "playlist")]
+    Playlist,
+    /// A webhook, by its name. Its deliveries stay pending while paused
+    #[serde(rename = "destination")]
+    Destination,
+}
+
+impl PauseScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PauseScope::Playlist => "playlist",
+            PauseScope::Destination => "destination",
+        }
+    }
+}
+
+/// A paused playlist or destination
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Pause {
+    pub scope: String,
+    pub name: String,
+}
+
+impl Pause {
+    pub fn is(&self, scope: PauseScope, name: &str) -> bool {
+        self.scope == scope.as_str() && self.name == name
+    }
+}
+
+/// How the last poll of a playlist went: `fetched`, `unchanged` or `failed`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PollStatus {
+    pub playlist: String,
+    pub polled_at: String,
+    pub status: String,
+}
+
+/// What `upsert_entry` did with a feed entry
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upsert {
+    /// The video is new to this playlist
+    Inserted,
+    /// The video was known, but its title or `updated` timestamp changed
+    Changed,
+    Unchanged,
+}
+
+/// A known video changed when its title did, or when its `updated` timestamp moved.
+/// Rows stored before `updated` was tracked have it empty, which doesn't count as a change
+fn is_changed(title: &str, updated: &str, entry: &Entry) -> bool {
+    title != entry.title || (!updated.is_empty() && updated != entry.updated)
+}
+
+/// The video id YouTube uses in watch URLs, without the Atom `yt:video:` prefix
+pub fn video_id(entry: &Entry) -> String {
+    entry.id.replace("yt:video:", "")
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub enum StorageBackend {
+    #[serde(rename = "sqlite")]
+    Sqlite,
+    #[serde(rename = "postgres")]
+    Postgres,
+    /// Nothing survives the process, only useful for tests
+    #[serde(rename = "memory")]
+    Memory,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// The database file for sqlite, or the connection string for postgres
+    pub dsn: Option<String>,
+}
+
+/// A postgres connection string may hold a password, so the dsn is never printed
+impl std::fmt::Debug for StorageConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("StorageConfig")
+            .field("backend", &self.backend)
+            .field("dsn", &self.dsn.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        Self {
+            backend: StorageBackend::Sqlite,
+            dsn: None,
+        }
+    }
+}
+
+/// Opens the configured backend and brings its schema up to date
+pub async fn open(config: &StorageConfig) -> anyhow::Result<Arc<dyn VideoStore>> {
+    let store: Arc<dyn VideoStore> = match config.backend {
+        StorageBackend::Sqlite => {
+            let path = config.dsn.as_deref().unwrap_or("videos.sqlite3");
+            Arc::new(SqliteStore::open(Path::new(path))?)
+        }
+        StorageBackend::Postgres =>
+            match &config.dsn {
+                Some(dsn) => Arc::new(PostgresStore::connect(dsn).await?),
+                None => {
+                    return Err(anyhow::anyhow!("The postgres storage backend requires a dsn"));
+                }
+            }
+        StorageBackend::Memory => Arc::new(MemoryStore::default()),
+    };
+
+    Ok(store)
+}
+
+/// Opens the configured backend without creating or migrating anything, for a run that must not
+/// change the database. Fails when the schema isn't up to date
+pub async fn open_existing(config: &StorageConfig) -> anyhow::Result<Arc<dyn VideoStore>> {
+    let store: Arc<dyn VideoStore> = match config.backend {
+        StorageBackend::Sqlite => {
+            let path = config.dsn.as_deref().unwrap_or("videos.sqlite3");
+            Arc::new(SqliteStore::open_existing(Path::new(path))?)
+        }
+        StorageBackend::Postgres =>
+            match &config.dsn {
+                Some(dsn) => Arc::new(PostgresStore::connect_existing(dsn).await?),
+                None => {
+                    return Err(anyhow::anyhow!("The postgres storage backend requires a dsn"));
+                }
+            }
+        StorageBackend::Memory => Arc::new(MemoryStore::default()),
+    };
+
+    Ok(store)
+}
+
+/// Videos, their deliveries, and the per-playlist cursor recording which playlists
+/// have been ingested before
+#[async_trait]
+pub trait VideoStore: Send + Sync {
+    /// Stores a feed entry and adds it to a playlist. A video can be in any number of playlists.
+    /// When a known video's title or `updated` timestamp changed, the video is updated and its
+    /// delivered Discord messages are flagged for an edit
+    async fn upsert_entry(&self, playlist: &str, entry: &Entry, hooked: bool) -> anyhow::Result<Upsert>;
+
+    async fn mark_hooked(&self, video: &str, playlist: &str) -> anyhow::Result<()>;
+
+    /// Ids of the videos in a playlist that haven't gone out to every sink yet
+    async fn unhooked_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<String>>;
+
+    /// Videos in a playlist with at least one pending delivery, oldest first
+    async fn pending_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>>;
+
+    /// Whether the relay has ingested this playlist before
+    async fn is_known_playlist(&self, playlist: &str) -> anyhow::Result<bool>;
+
+    async fn mark_known_playlist(&self, playlist: &str) -> anyhow::Result<()>;
+
+    /// Records a pending delivery for the key if one doesn't already exist
+    async fn enqueue(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()>;
+
+    /// Whether the delivery is pending and its backoff, if any, has elapsed
+    async fn is_due(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool>;
+
+    /// Records a successful delivery along with what the destination handed back
+    async fn mark_delivered(&self, key: &DeliveryKey<'_>, receipt: &Receipt) -> anyhow::Result<()>;
+
+    /// Records a failed attempt and schedules the next one according to the policy.
+    /// Once the policy is exhausted the delivery is moved to the dead-letter state.
+    /// Returns the status the delivery ended up in
+    async fn mark_failed(
+        &self,
+        key: &DeliveryKey<'_>,
+        error: &str,
+        policy: &RetryPolicy
+    ) -> anyhow::Result<DeliveryStatus>;
+
+    /// Marks every outstanding delivery of a video as skipped, so it is never sent
+    async fn skip_pending(&self, video: &str, playlist: &str) -> anyhow::Result<usize>;
+
+    /// Whether the video already has a delivery to this target, in any playlist
+    async fn has_delivery(
+        &self,
+        video: &str,
+        destination: &WebhookType,
+        target: &str
+    ) -> anyhow::Result<bool>;
+
+    /// The number of deliveries for a video in a playlist that have not gone out yet
+    async fn pending_count(&self, video: &str, playlist: &str) -> anyhow::Result<i64>;
+
+    /// Delivered messages in a playlist whose video changed since they were sent
+    async fn pending_edits(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>>;
+
+    /// Clears the edit flag once the message has been updated
+    async fn mark_edited(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()>;
+
+    /// Videos delivered for a playlist that haven't been found removed yet
+    async fn announced_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>>;
+
+    /// Flags a video as removed from YouTube. Its outstanding deliveries are skipped
+    async fn mark_removed(&self, video: &str) -> anyhow::Result<()>;
+
+    /// Flags a video as taken out of one playlist. Its outstanding deliveries for that playlist are skipped
+    async fn mark_removed_from(&self, video: &str, playlist: &str) -> anyhow::Result<()>;
+
+    /// Delivered announcements in a playlist whose video was removed from YouTube or from the playlist since
+    async fn pending_retractions(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>>;
+
+    async fn mark_retracted(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()>;
+
+    /// Every video with the playlists it is in, newest first. `playlist` narrows it down to one playlist
+    async fn videos(&self, playlist: Option<&str>) -> anyhow::Result<Vec<Video>>;
+
+    /// Every delivery of a video, in any state
+    async fn deliveries(&self, video: &str) -> anyhow::Result<Vec<Delivery>>;
+
+    /// Moves every delivery of a video back to pending with a fresh attempt budget, whatever
+    /// state it is in, so it is sent again. `destination` narrows down which deliveries are replayed
+    async fn replay(&self, video: &str, destination: Option<&str>) -> anyhow::Result<usize>;
+
+    /// The validators and body hash of the last response stored for a feed URL
+    async fn feed_cache(&self, url: &str) -> anyhow::Result<Option<FeedCache>>;
+
+    /// Records a feed response, once its entries are stored
+    async fn save_feed_cache(&self, cache: &FeedCache) -> anyhow::Result<()>;
+
+    /// Moves one delivery back to pending with a fresh attempt budget, whatever state it is in.
+    /// Returns whether there was such a delivery
+    async fn requeue_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool>;
+
+    /// Marks one outstanding delivery as skipped. Returns whether there was one
+    async fn skip_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool>;
+
+    /// Everything paused through the admin API
+    async fn pauses(&self) -> anyhow::Result<Vec<Pause>>;
+
+    async fn set_paused(&self, scope: PauseScope, name: &str, paused: bool) -> anyhow::Result<()>;
+
+    /// Records how polling a playlist went
+    async fn record_poll(&self, playlist: &str, status: &str) -> anyhow::Result<()>;
+
+    /// The last poll of every playlist that has been polled
+    async fn polls(&self) -> anyhow::Result<Vec<PollStatus>>;
+
+    /// Every delivery that has exhausted its retries
+    async fn dead_letters(&self) -> anyhow::Result<Vec<Delivery>>;
+
+    /// Moves dead-lettered deliveries back to pending with a fresh attempt budget.
+    /// `video` and `destination` narrow down which deliveries are requeued
+    async fn requeue(&self, video: Option<&str>, destination: Option<&str>) -> anyhow::Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, title: &str, updated: &str) -> Entry {
+        Entry {
+            title: title.to_string(),
+            id: format!("yt:video:{}", id),
+            published: "2024-01-01T00:00:00+00:00".to_string(),
+            updated: updated.to_string(),
+            author: None,
+        }
+    }
+
+    fn key<'a>(video: &'a str, playlist: &'a str) -> DeliveryKey<'a> {
+        DeliveryKey { video, playlist, destination: &WebhookType::Discord, target: "https://discord.test/1/token" }
+    }
+
+    /// What every backend has to agree on
+    async fn behaves(store: &dyn VideoStore) {
+        let first = entry("a", "First", "2024-01-01T00:00:00+00:00");
+        assert_eq!(store.upsert_entry("PL1", &first, false).await.unwrap(), Upsert::Inserted);
+        assert_eq!(store.upsert_entry("PL1", &first, false).await.unwrap(), Upsert::Unchanged);
+        assert_eq!(store.upsert_entry("PL2", &first, false).await.unwrap(), Upsert::Inserted);
+        assert!(store.is_known_playlist("PL1").await.unwrap());
+        assert!(!store.is_known_playlist("PL3").await.unwrap());
+        assert_eq!(store.unhooked_for_playlist("PL1").await.unwrap(), vec!["a".to_string()]);
+
+        // A delivery is retried with a backoff, then dead-lettered
+        let policy = RetryPolicy { max_attempts: 2, base_delay: 0, max_delay: 0, jitter: 0.0 };
+        store.enqueue(&key("a", "PL1")).await.unwrap();
+        assert!(store.is_due(&key("a", "PL1")).await.unwrap());
+        assert_eq!(store.pending_count("a", "PL1").await.unwrap(), 1);
+        assert_eq!(store.mark_failed(&key("a", "PL1"), "boom", &policy).await.unwrap(), DeliveryStatus::Pending);
+        assert_eq!(store.mark_failed(&key("a", "PL1"), "boom", &policy).await.unwrap(), DeliveryStatus::Dead);
+        let dead = store.dead_letters().await.unwrap();
+        assert_eq!(dead.len(), 1);
+        assert_eq!(dead[0].last_error.as_deref(), Some("boom"));
+
+        // Requeuing gives it a fresh start
+        assert_eq!(store.requeue(Some("a"), None).await.unwrap(), 1);
+        let requeued = &store.deliveries("a").await.unwrap()[0];
+        assert_eq!(requeued.status, DeliveryStatus::Pending.as_str());
+        assert_eq!(requeued.attempts, 0);
+        assert_eq!(requeued.last_error, None);
+        assert!(store.dead_letters().await.unwrap().is_empty());
+
+        let receipt = Receipt { message_id: Some("42".to_string()), ..Receipt::default() };
+        store.mark_delivered(&key("a", "PL1"), &receipt).await.unwrap();
+        store.mark_hooked("a", "PL1").await.unwrap();
+        assert!(!store.is_due(&key("a", "PL1")).await.unwrap());
+        assert!(store.unhooked_for_playlist("PL1").await.unwrap().is_empty());
+        assert!(
+            store.has_delivery("a", &WebhookType::Discord, "https://discord.test/1/token").await.unwrap()
+        );
+
+        // A new title flags the delivered message for an edit
+        let renamed = entry("a", "Renamed", "2024-01-02T00:00:00+00:00");
+        assert_eq!(store.upsert_entry("PL1", &renamed, false).await.unwrap(), Upsert::Changed);
+        let edits = store.pending_edits("PL1").await.unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].0.title, "Renamed");
+        assert_eq!(edits[0].1.message_id.as_deref(), Some("42"));
+        store.mark_edited(&key("a", "PL1")).await.unwrap();
+        assert!(store.pending_edits("PL1").await.unwrap().is_empty());
+
+        // A removed video is retracted where it was announced
+        assert_eq!(store.announced_for_playlist("PL1").await.unwrap().len(), 1);
+        store.mark_removed("a").await.unwrap();
+        assert!(store.announced_for_playlist("PL1").await.unwrap().is_empty());
+        assert_eq!(store.pending_retractions("PL1").await.unwrap().len(), 1);
+        store.mark_retracted(&key("a", "PL1")).await.unwrap();
+        assert!(store.pending_retractions("PL1").await.unwrap().is_empty());
+
+        // Taking a video out of one playlist retracts it there only
+        let second = entry("b", "Second", "2024-01-01T00:00:00+00:00");
+        store.upsert_entry("PL1", &second, false).await.unwrap();
+        store.upsert_entry("PL2", &second, false).await.unwrap();
+        store.enqueue(&key("b", "PL1")).await.unwrap();
+        store.enqueue(&key("b", "PL2")).await.unwrap();
+        store.mark_delivered(&key("b", "PL1"), &Receipt::default()).await.unwrap();
+        store.mark_removed_from("b", "PL2").await.unwrap();
+        assert_eq!(store.deliveries("b").await.unwrap()[1].status, DeliveryStatus::Skipped.as_str());
+        store.mark_delivered(&key("b", "PL2"), &Receipt::default()).await.unwrap();
+        assert_eq!(store.announced_for_playlist("PL1").await.unwrap().len(), 1);
+        assert!(store.announced_for_playlist("PL2").await.unwrap().is_empty());
+        assert!(store.pending_retractions("PL1").await.unwrap().is_empty());
+        assert_eq!(store.pending_retractions("PL2").await.unwrap().len(), 1);
+
+        store.set_paused(PauseScope::Playlist, "PL2", true).await.unwrap();
+        assert!(store.pauses().await.unwrap().iter().any(|p| p.is(PauseScope::Playlist, "PL2")));
+        store.set_paused(PauseScope::Playlist, "PL2", false).await.unwrap();
+        assert!(store.pauses().await.unwrap().is_empty());
+
+        let url = "https://www.youtube.com/feeds/videos.xml?playlist_id=PL1";
+        assert_eq!(store.feed_cache(url).await.unwrap(), None);
+        let mut cache = FeedCache {
+            url: url.to_string(),
+            etag: Some("\"1\"".to_string()),
+            last_modified: Some("Mon, 01 Jan 2024 00:00:00 GMT".to_string()),
+            hash: "aa".to_string(),
+        };
+        store.save_feed_cache(&cache).await.unwrap();
+        assert_eq!(store.feed_cache(url).await.unwrap(), Some(cache.clone()));
+        cache.etag = Some("\"2\"".to_string());
+        cache.last_modified = None;
+        cache.hash = "bb".to_string();
+        store.save_feed_cache(&cache).await.unwrap();
+        assert_eq!(store.feed_cache(url).await.unwrap(), Some(cache));
+        assert_eq!(store.feed_cache("https://www.youtube.com/feeds/videos.xml?playlist_id=PL2").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn memory_store() {
+        behaves(&MemoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn sqlite_store() {
+        behaves(&SqliteStore::open(Path::new(":memory:")).unwrap()).await;
+    }
+
+    /// Needs `TEST_POSTGRES_URL`, and is skipped without it
+    #[tokio::test]
+    async fn postgres_store() {
+        if let Some(store) = PostgresStore::scratch("behaves").await {
+            behaves(&store).await;
+        }
+    }
+}
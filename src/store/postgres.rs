@@ -0,0 +1,713 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use deadpool_postgres::{ Manager, ManagerConfig, Object, Pool, RecyclingMethod };
+use tokio_postgres::{ NoTls, Row };
+
+use super::{ is_changed, video_id, FeedCache, Pause, PauseScope, PollStatus, Upsert, Video, VideoStore };
+use crate::data::Entry;
+use crate::delivery::{ Delivery, DeliveryKey, DeliveryStatus, Receipt };
+use crate::WebhookType;
+use crate::retry::RetryPolicy;
+
+/// Connections shared by the relay's concurrent polls and servers
+const POOL_SIZE: usize = 4;
+
+/// Schema versions for postgres, recorded in `schema_version`.
+/// Every statement is idempotent so instances starting together can't trip over each other
+const MIGRATIONS: &[(i32, &str, &str)] = &[
+    (
+        1,
+        "baseline",
+        "CREATE TABLE IF NOT EXISTS video (id VARCHAR(255) PRIMARY KEY, title VARCHAR(255), author VARCHAR(255) NOT NULL DEFAULT '', \"timestamp\" TEXT, updated VARCHAR(255) NOT NULL DEFAULT '', removed BOOLEAN NOT NULL DEFAULT false);
+        CREATE INDEX IF NOT EXISTS video_timestamp ON video (\"timestamp\");
+        CREATE TABLE IF NOT EXISTS playlist (id VARCHAR(255) PRIMARY KEY, seen_at TIMESTAMPTZ NOT NULL DEFAULT now());
+        CREATE TABLE IF NOT EXISTS playlist_video (playlist VARCHAR(255) NOT NULL, video VARCHAR(255) NOT NULL, hooked INTEGER NOT NULL DEFAULT 0, removed BOOLEAN NOT NULL DEFAULT false, added_at TIMESTAMPTZ NOT NULL DEFAULT now(), PRIMARY KEY (playlist, video));
+        CREATE INDEX IF NOT EXISTS playlist_video_video ON playlist_video (video);
+        CREATE TABLE IF NOT EXISTS delivery (video VARCHAR(255) NOT NULL, playlist VARCHAR(255) NOT NULL, destination VARCHAR(32) NOT NULL, target VARCHAR(255) NOT NULL, status VARCHAR(16) NOT NULL DEFAULT 'pending', attempts INTEGER NOT NULL DEFAULT 0, last_error TEXT, next_attempt_at TIMESTAMPTZ, created_at TIMESTAMPTZ NOT NULL DEFAULT now(), updated_at TIMESTAMPTZ NOT NULL DEFAULT now(), delivered_at TIMESTAMPTZ, message_id VARCHAR(255), thread_id VARCHAR(255), record_cid VARCHAR(255), edit_pending BOOLEAN NOT NULL DEFAULT false, PRIMARY KEY (video, playlist, destination, target));
+        CREATE TABLE IF NOT EXISTS feed_cache (url TEXT PRIMARY KEY, etag VARCHAR(255), last_modified VARCHAR(255), hash VARCHAR(64) NOT NULL, fetched_at TIMESTAMPTZ NOT NULL DEFAULT now());
+        CREATE TABLE IF NOT EXISTS pause (scope VARCHAR(32) NOT NULL, name VARCHAR(255) NOT NULL, paused_at TIMESTAMPTZ NOT NULL DEFAULT now(), PRIMARY KEY (scope, name));
+        CREATE TABLE IF NOT EXISTS poll (playlist VARCHAR(255) PRIMARY KEY, polled_at TIMESTAMPTZ NOT NULL DEFAULT now(), status VARCHAR(16) NOT NULL);",
+    ),
+];
+
+/// A video as seen from one of its playlists
+const VIDEO_SELECT: &str =
+    "SELECT video.id AS id, playlist_video.playlist AS playlist, video.title AS title, video.author AS author, video.\"timestamp\" AS \"timestamp\", playlist_video.hooked AS hooked, video.updated AS updated FROM video JOIN playlist_video ON playlist_video.video = video.id";
+
+fn video_from_row(row: &Row) -> Result<Video, tokio_postgres::Error> {
+    Ok(Video {
+        id: row.try_get("id")?,
+        playlist: row.try_get("playlist")?,
+        title: row.try_get("title")?,
+        author: row.try_get("author")?,
+        timestamp: row.try_get("timestamp")?,
+        hooked: row.try_get("hooked")?,
+        updated: row.try_get("updated")?,
+    })
+}
+
+fn delivery_from_row(row: &Row) -> Result<Delivery, tokio_postgres::Error> {
+    Ok(Delivery {
+        video: row.try_get("video")?,
+        playlist: row.try_get("playlist")?,
+        destination: row.try_get("destination")?,
+        target: row.try_get("target")?,
+        status: row.try_get("status")?,
+        attempts: row.try_get::<_, i32>("attempts")?.max(0) as u32,
+        last_error: row.try_get("last_error")?,
+        next_attempt_at: row.try_get("next_attempt_at")?,
+        updated_at: row.try_get("updated_at")?,
+        message_id: row.try_get("message_id")?,
+        thread_id: row.try_get("thread_id")?,
+        record_cid: row.try_get("record_cid")?,
+        edit_pending: row.try_get("edit_pending")?,
+    })
+}
+
+/// Connections are opened as needed. One the server closed is dropped on its way out of the pool,
+/// so a restarted database is reconnected to
+fn pool(config: tokio_postgres::Config) -> anyhow::Result<Pool> {
+    let manager = Manager::from_config(config, NoTls, ManagerConfig { recycling_method: RecyclingMethod::Fast });
+    Ok(Pool::builder(manager).max_size(POOL_SIZE).build()?)
+}
+
+/// Stores videos, deliveries and playlists in PostgreSQL, so several relay instances can share them
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    /// Connects to the database and brings its schema up to date
+    pub async fn connect(dsn: &str) -> anyhow::Result<Self> {
+        let store = Self { pool: pool(dsn.parse()?)? };
+        store.migrate().await?;
+        Ok(store)
+    }
+
+    /// Connects to the database, failing when its schema isn't up to date
+    pub async fn connect_existing(dsn: &str) -> anyhow::Result<Self> {
+        let store = Self { pool: pool(dsn.parse()?)? };
+        store.check().await?;
+        Ok(store)
+    }
+
+    /// Fails unless the database is at the latest schema version, without changing it
+    async fn check(&self) -> anyhow::Result<()> {
+        let current = self.version().await?;
+        let latest = MIGRATIONS.last().map_or(0, |(version, _, _)| *version);
+        if current != latest {
+            return Err(
+                anyhow!(
+                    "The database is at schema version {}, but this relay expects {}. Run it without --dry-run to migrate",
+                    current,
+                    latest
+                )
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The last migration applied, 0 for a database that has none
+    async fn version(&self) -> anyhow::Result<i32> {
+        let client = self.client().await?;
+        let exists: bool = client
+            .query_one("SELECT to_regclass('schema_version') IS NOT NULL AS exists", &[]).await?
+            .try_get("exists")?;
+        if !exists {
+            return Ok(0);
+        }
+
+        Ok(
+            client
+                .query_one("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version", &[]).await?
+                .try_get("version")?
+        )
+    }
+
+    async fn client(&self) -> anyhow::Result<Object> {
+        Ok(self.pool.get().await?)
+    }
+
+    async fn migrate(&self) -> anyhow::Result<()> {
+        self.client().await?.batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_version (version INTEGER PRIMARY KEY)"
+        ).await?;
+        let current: i32 = self.client().await?
+            .query_one("SELECT COALESCE(MAX(version), 0) AS version FROM schema_version", &[]).await?
+            .try_get("version")?;
+
+        for (version, name, sql) in MIGRATIONS.iter().filter(|(version, _, _)| *version > current) {
+            self.client().await?.batch_execute(
+                &format!(
+                    "BEGIN; {} INSERT INTO schema_version (version) VALUES ({}) ON CONFLICT DO NOTHING; COMMIT;",
+                    sql,
+                    version
+                )
+            ).await?;
+            tracing::info!("Applied migration {}: {}", version, name);
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl VideoStore for PostgresStore {
+    async fn upsert_entry(&self, playlist: &str, entry: &Entry, hooked: bool) -> anyhow::Result<Upsert> {
+        let id = video_id(entry);
+        let existing = self.client().await?.query_opt(
+            "SELECT title, updated FROM video WHERE id = $1",
+            &[&id]
+        ).await?;
+
+        let hooked = hooked as i32;
+        let linked =
+            self.client().await?.execute(
+                "INSERT INTO playlist_video (playlist, video, hooked) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+                &[&playlist, &id, &hooked]
+            ).await? > 0;
+        let unchanged = if linked { Upsert::Inserted } else { Upsert::Unchanged };
+
+        let (title, updated): (String, String) = match existing {
+            Some(row) => (row.try_get("title")?, row.try_get("updated")?),
+            None => {
+                let author = entry.author.as_ref().map_or("", |author| author.name.as_str());
+                self.client().await?.execute(
+                    "INSERT INTO video (id, title, author, \"timestamp\", updated) VALUES ($1, $2, $3, $4, $5) ON CONFLICT (id) DO NOTHING",
+                    &[&id, &entry.title, &author, &entry.published, &entry.updated]
+                ).await?;
+
+                return Ok(unchanged);
+            }
+        };
+
+        if title == entry.title && updated == entry.updated {
+            return Ok(unchanged);
+        }
+
+        self.client().await?.execute(
+            "UPDATE video SET title = $2, updated = $3 WHERE id = $1",
+            &[&id, &entry.title, &entry.updated]
+        ).await?;
+
+        if !is_changed(&title, &updated, entry) {
+            return Ok(unchanged);
+        }
+
+        self.client().await?.execute(
+            "UPDATE delivery SET edit_pending = true WHERE video = $1 AND destination = $2 AND status = $3 AND message_id IS NOT NULL",
+            &[&id, &WebhookType::Discord.as_str(), &DeliveryStatus::Delivered.as_str()]
+        ).await?;
+
+        Ok(Upsert::Changed)
+    }
+
+    async fn mark_hooked(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "UPDATE playlist_video SET hooked = 1 WHERE video = $1 AND playlist = $2",
+            &[&video, &playlist]
+        ).await?;
+        Ok(())
+    }
+
+    async fn unhooked_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<String>> {
+        let rows = self.client().await?.query(
+            "SELECT video AS id FROM playlist_video WHERE hooked = 0 AND playlist = $1",
+            &[&playlist]
+        ).await?;
+        let ids = rows
+            .iter()
+            .map(|row| row.try_get("id"))
+            .collect::<Result<Vec<String>, _>>()?;
+
+        Ok(ids)
+    }
+
+    async fn pending_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let rows = self.client().await?.query(
+            &format!(
+                "{} WHERE playlist_video.playlist = $1 AND EXISTS (SELECT 1 FROM delivery WHERE delivery.video = video.id AND delivery.playlist = playlist_video.playlist AND delivery.status = $2) ORDER BY video.\"timestamp\" ASC",
+                VIDEO_SELECT
+            ),
+            &[&playlist, &DeliveryStatus::Pending.as_str()]
+        ).await?;
+        let videos = rows.iter().map(video_from_row).collect::<Result<Vec<Video>, _>>()?;
+
+        Ok(videos)
+    }
+
+    async fn is_known_playlist(&self, playlist: &str) -> anyhow::Result<bool> {
+        let known = self.client().await?
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM playlist WHERE id = $1) OR EXISTS (SELECT 1 FROM playlist_video WHERE playlist = $1) AS known",
+                &[&playlist]
+            ).await?
+            .try_get("known")?;
+
+        Ok(known)
+    }
+
+    async fn mark_known_playlist(&self, playlist: &str) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "INSERT INTO playlist (id) VALUES ($1) ON CONFLICT DO NOTHING",
+            &[&playlist]
+        ).await?;
+        Ok(())
+    }
+
+    async fn enqueue(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "INSERT INTO delivery (video, playlist, destination, target, status) VALUES ($1, $2, $3, $4, $5) ON CONFLICT DO NOTHING",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Pending.as_str(),
+            ]
+        ).await?;
+        Ok(())
+    }
+
+    async fn is_due(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let row = self.client().await?.query_opt(
+            "SELECT status = $5 AND (next_attempt_at IS NULL OR next_attempt_at <= now()) AS due FROM delivery WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Pending.as_str(),
+            ]
+        ).await?;
+
+        match row {
+            Some(row) => Ok(row.try_get("due")?),
+            None => Ok(false),
+        }
+    }
+
+    async fn mark_delivered(&self, key: &DeliveryKey<'_>, receipt: &Receipt) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "UPDATE delivery SET status = $5, attempts = attempts + 1, last_error = NULL, next_attempt_at = NULL, message_id = $6, thread_id = $7, record_cid = $8, updated_at = now(), delivered_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Delivered.as_str(),
+                &receipt.message_id,
+                &receipt.thread_id,
+                &receipt.record_cid,
+            ]
+        ).await?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        key: &DeliveryKey<'_>,
+        error: &str,
+        policy: &RetryPolicy
+    ) -> anyhow::Result<DeliveryStatus> {
+        let row = self.client().await?.query_opt(
+            "SELECT attempts FROM delivery WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[&key.video, &key.playlist, &key.destination.as_str(), &key.target]
+        ).await?;
+        let attempts = match row {
+            Some(row) => row.try_get::<_, i32>("attempts")?.max(0) as u32,
+            None => 0,
+        } + 1;
+
+        let status = if policy.is_exhausted(attempts) {
+            DeliveryStatus::Dead
+        } else {
+            DeliveryStatus::Pending
+        };
+        let backoff = policy.backoff(attempts).as_secs_f64();
+
+        self.client().await?.execute(
+            "UPDATE delivery SET status = $5, attempts = $6, last_error = $7, next_attempt_at = now() + make_interval(secs => $8), updated_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &status.as_str(),
+                &(attempts as i32),
+                &error,
+                &backoff,
+            ]
+        ).await?;
+
+        Ok(status)
+    }
+
+    async fn skip_pending(&self, video: &str, playlist: &str) -> anyhow::Result<usize> {
+        let skipped = self.client().await?.execute(
+            "UPDATE delivery SET status = $3, next_attempt_at = NULL, updated_at = now() WHERE video = $1 AND playlist = $2 AND status IN ($4, $5)",
+            &[
+                &video,
+                &playlist,
+                &DeliveryStatus::Skipped.as_str(),
+                &DeliveryStatus::Pending.as_str(),
+                &DeliveryStatus::Dead.as_str(),
+            ]
+        ).await?;
+
+        Ok(skipped as usize)
+    }
+
+    async fn has_delivery(
+        &self,
+        video: &str,
+        destination: &WebhookType,
+        target: &str
+    ) -> anyhow::Result<bool> {
+        let exists = self.client().await?
+            .query_one(
+                "SELECT EXISTS (SELECT 1 FROM delivery WHERE video = $1 AND destination = $2 AND target = $3) AS exists",
+                &[&video, &destination.as_str(), &target]
+            ).await?
+            .try_get("exists")?;
+
+        Ok(exists)
+    }
+
+    async fn pending_count(&self, video: &str, playlist: &str) -> anyhow::Result<i64> {
+        let count = self.client().await?
+            .query_one(
+                "SELECT COUNT(*) AS count FROM delivery WHERE video = $1 AND playlist = $2 AND status = $3",
+                &[&video, &playlist, &DeliveryStatus::Pending.as_str()]
+            ).await?
+            .try_get("count")?;
+
+        Ok(count)
+    }
+
+    async fn pending_edits(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let rows = self.client().await?.query(
+            "SELECT video, playlist, destination, target, status, attempts, last_error, next_attempt_at::text AS next_attempt_at, updated_at::text AS updated_at, message_id, thread_id, record_cid, edit_pending FROM delivery WHERE playlist = $1 AND edit_pending",
+            &[&playlist]
+        ).await?;
+
+        let mut edits = Vec::new();
+        for row in &rows {
+            let delivery = delivery_from_row(row)?;
+            let video = self.client().await?.query_opt(
+                &format!("{} WHERE video.id = $1 AND playlist_video.playlist = $2", VIDEO_SELECT),
+                &[&delivery.video, &delivery.playlist]
+            ).await?;
+            if let Some(video) = video {
+                edits.push((video_from_row(&video)?, delivery));
+            }
+        }
+
+        Ok(edits)
+    }
+
+    async fn mark_edited(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "UPDATE delivery SET edit_pending = false, updated_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[&key.video, &key.playlist, &key.destination.as_str(), &key.target]
+        ).await?;
+        Ok(())
+    }
+
+    async fn announced_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let rows = self.client().await?.query(
+            &format!(
+                "{} WHERE playlist_video.playlist = $1 AND NOT video.removed AND NOT playlist_video.removed AND EXISTS (SELECT 1 FROM delivery WHERE delivery.video = video.id AND delivery.playlist = $1 AND delivery.status = $2)",
+                VIDEO_SELECT
+            ),
+            &[&playlist, &DeliveryStatus::Delivered.as_str()]
+        ).await?;
+        let videos = rows.iter().map(video_from_row).collect::<Result<Vec<Video>, _>>()?;
+
+        Ok(videos)
+    }
+
+    async fn mark_removed(&self, video: &str) -> anyhow::Result<()> {
+        self.client().await?.execute("UPDATE video SET removed = true WHERE id = $1", &[&video]).await?;
+        self.client().await?.execute(
+            "UPDATE delivery SET status = $2, next_attempt_at = NULL, updated_at = now() WHERE video = $1 AND status IN ($3, $4)",
+            &[
+                &video,
+                &DeliveryStatus::Skipped.as_str(),
+                &DeliveryStatus::Pending.as_str(),
+                &DeliveryStatus::Dead.as_str(),
+            ]
+        ).await?;
+        Ok(())
+    }
+
+    async fn mark_removed_from(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "UPDATE playlist_video SET removed = true WHERE video = $1 AND playlist = $2",
+            &[&video, &playlist]
+        ).await?;
+        self.client().await?.execute(
+            "UPDATE delivery SET status = $3, next_attempt_at = NULL, updated_at = now() WHERE video = $1 AND playlist = $2 AND status IN ($4, $5)",
+            &[
+                &video,
+                &playlist,
+                &DeliveryStatus::Skipped.as_str(),
+                &DeliveryStatus::Pending.as_str(),
+                &DeliveryStatus::Dead.as_str(),
+            ]
+        ).await?;
+        Ok(())
+    }
+
+    async fn pending_retractions(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let rows = self.client().await?.query(
+            "SELECT video, playlist, destination, target, status, attempts, last_error, next_attempt_at::text AS next_attempt_at, updated_at::text AS updated_at, message_id, thread_id, record_cid, edit_pending FROM delivery WHERE playlist = $1 AND status = $2 AND (video IN (SELECT id FROM video WHERE removed) OR video IN (SELECT video FROM playlist_video WHERE playlist = $1 AND removed))",
+            &[&playlist, &DeliveryStatus::Delivered.as_str()]
+        ).await?;
+
+        let mut retractions = Vec::new();
+        for row in &rows {
+            let delivery = delivery_from_row(row)?;
+            let video = self.client().await?.query_opt(
+                &format!("{} WHERE video.id = $1 AND playlist_video.playlist = $2", VIDEO_SELECT),
+                &[&delivery.video, &delivery.playlist]
+            ).await?;
+            if let Some(video) = video {
+                retractions.push((video_from_row(&video)?, delivery));
+            }
+        }
+
+        Ok(retractions)
+    }
+
+    async fn mark_retracted(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "UPDATE delivery SET status = $5, edit_pending = false, updated_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Retracted.as_str(),
+            ]
+        ).await?;
+        Ok(())
+    }
+
+    async fn videos(&self, playlist: Option<&str>) -> anyhow::Result<Vec<Video>> {
+        let rows = self.client().await?.query(
+            &format!(
+                "{} WHERE ($1::text IS NULL OR playlist_video.playlist = $1) ORDER BY video.\"timestamp\" DESC",
+                VIDEO_SELECT
+            ),
+            &[&playlist]
+        ).await?;
+        let videos = rows.iter().map(video_from_row).collect::<Result<Vec<Video>, _>>()?;
+
+        Ok(videos)
+    }
+
+    async fn deliveries(&self, video: &str) -> anyhow::Result<Vec<Delivery>> {
+        let rows = self.client().await?.query(
+            "SELECT video, playlist, destination, target, status, attempts, last_error, next_attempt_at::text AS next_attempt_at, updated_at::text AS updated_at, message_id, thread_id, record_cid, edit_pending FROM delivery WHERE video = $1 ORDER BY playlist, destination, target",
+            &[&video]
+        ).await?;
+        let deliveries = rows.iter().map(delivery_from_row).collect::<Result<Vec<Delivery>, _>>()?;
+
+        Ok(deliveries)
+    }
+
+    async fn replay(&self, video: &str, destination: Option<&str>) -> anyhow::Result<usize> {
+        let replayed = self.client().await?.execute(
+            "UPDATE delivery SET status = $1, attempts = 0, last_error = NULL, next_attempt_at = NULL, edit_pending = false, updated_at = now() WHERE video = $2 AND ($3::text IS NULL OR destination = $3)",
+            &[&DeliveryStatus::Pending.as_str(), &video, &destination]
+        ).await?;
+
+        Ok(replayed as usize)
+    }
+
+    async fn feed_cache(&self, url: &str) -> anyhow::Result<Option<FeedCache>> {
+        let row = self.client().await?.query_opt(
+            "SELECT url, etag, last_modified, hash FROM feed_cache WHERE url = $1",
+            &[&url]
+        ).await?;
+
+        let cache = match row {
+            Some(row) =>
+                Some(FeedCache {
+                    url: row.try_get("url")?,
+                    etag: row.try_get("etag")?,
+                    last_modified: row.try_get("last_modified")?,
+                    hash: row.try_get("hash")?,
+                }),
+            None => None,
+        };
+
+        Ok(cache)
+    }
+
+    async fn save_feed_cache(&self, cache: &FeedCache) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "INSERT INTO feed_cache (url, etag, last_modified, hash) VALUES ($1, $2, $3, $4) ON CONFLICT (url) DO UPDATE SET etag = excluded.etag, last_modified = excluded.last_modified, hash = excluded.hash, fetched_at = now()",
+            &[&cache.url, &cache.etag, &cache.last_modified, &cache.hash]
+        ).await?;
+        Ok(())
+    }
+
+    async fn requeue_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let requeued = self.client().await?.execute(
+            "UPDATE delivery SET status = $5, attempts = 0, last_error = NULL, next_attempt_at = NULL, edit_pending = false, updated_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Pending.as_str(),
+            ]
+        ).await?;
+
+        Ok(requeued > 0)
+    }
+
+    async fn skip_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let skipped = self.client().await?.execute(
+            "UPDATE delivery SET status = $5, next_attempt_at = NULL, updated_at = now() WHERE video = $1 AND playlist = $2 AND destination = $3 AND target = $4 AND status IN ($6, $7)",
+            &[
+                &key.video,
+                &key.playlist,
+                &key.destination.as_str(),
+                &key.target,
+                &DeliveryStatus::Skipped.as_str(),
+                &DeliveryStatus::Pending.as_str(),
+                &DeliveryStatus::Dead.as_str(),
+            ]
+        ).await?;
+
+        Ok(skipped > 0)
+    }
+
+    async fn pauses(&self) -> anyhow::Result<Vec<Pause>> {
+        let rows = self.client().await?.query("SELECT scope, name FROM pause ORDER BY scope, name", &[]).await?;
+        let pauses = rows
+            .iter()
+            .map(|row| Ok(Pause { scope: row.try_get("scope")?, name: row.try_get("name")? }))
+            .collect::<Result<Vec<Pause>, tokio_postgres::Error>>()?;
+
+        Ok(pauses)
+    }
+
+    async fn set_paused(&self, scope: PauseScope, name: &str, paused: bool) -> anyhow::Result<()> {
+        let sql = if paused {
+            "INSERT INTO pause (scope, name) VALUES ($1, $2) ON CONFLICT DO NOTHING"
+        } else {
+            "DELETE FROM pause WHERE scope = $1 AND name = $2"
+        };
+        self.client().await?.execute(sql, &[&scope.as_str(), &name]).await?;
+        Ok(())
+    }
+
+    async fn record_poll(&self, playlist: &str, status: &str) -> anyhow::Result<()> {
+        self.client().await?.execute(
+            "INSERT INTO poll (playlist, status) VALUES ($1, $2) ON CONFLICT (playlist) DO UPDATE SET status = excluded.status, polled_at = now()",
+            &[&playlist, &status]
+        ).await?;
+        Ok(())
+    }
+
+    async fn polls(&self) -> anyhow::Result<Vec<PollStatus>> {
+        let rows = self.client().await?.query(
+            "SELECT playlist, polled_at::text AS polled_at, status FROM poll ORDER BY playlist",
+            &[]
+        ).await?;
+        let polls = rows
+            .iter()
+            .map(|row| {
+                Ok(PollStatus {
+                    playlist: row.try_get("playlist")?,
+                    polled_at: row.try_get("polled_at")?,
+                    status: row.try_get("status")?,
+                })
+            })
+            .collect::<Result<Vec<PollStatus>, tokio_postgres::Error>>()?;
+
+        Ok(polls)
+    }
+
+    async fn dead_letters(&self) -> anyhow::Result<Vec<Delivery>> {
+        let rows = self.client().await?.query(
+            "SELECT video, playlist, destination, target, status, attempts, last_error, next_attempt_at::text AS next_attempt_at, updated_at::text AS updated_at, message_id, thread_id, record_cid, edit_pending FROM delivery WHERE status = $1 ORDER BY updated_at ASC",
+            &[&DeliveryStatus::Dead.as_str()]
+        ).await?;
+        let deliveries = rows.iter().map(delivery_from_row).collect::<Result<Vec<Delivery>, _>>()?;
+
+        Ok(deliveries)
+    }
+
+    async fn requeue(&self, video: Option<&str>, destination: Option<&str>) -> anyhow::Result<usize> {
+        let requeued = self.client().await?.execute(
+            "UPDATE delivery SET status = $1, attempts = 0, last_error = NULL, next_attempt_at = NULL, updated_at = now() WHERE status = $2 AND ($3::text IS NULL OR video = $3) AND ($4::text IS NULL OR destination = $4)",
+            &[&DeliveryStatus::Pending.as_str(), &DeliveryStatus::Dead.as_str(), &video, &destination]
+        ).await?;
+
+        Ok(requeued as usize)
+    }
+}
+
+/// A pool on an empty schema of its own in the database `TEST_POSTGRES_URL` points at.
+/// `None` when the variable isn't set, so the tests that need a server are skipped
+#[cfg(test)]
+async fn scratch_pool(name: &str) -> Option<Pool> {
+    let dsn = match std::env::var("TEST_POSTGRES_URL") {
+        Ok(dsn) => dsn,
+        Err(_) => {
+            eprintln!("TEST_POSTGRES_URL isn't set, skipping the postgres test {}", name);
+            return None;
+        }
+    };
+
+    let schema = format!("relay_test_{}_{}", name, std::process::id());
+    let (client, connection) = tokio_postgres::connect(&dsn, NoTls).await.unwrap();
+    tokio::spawn(connection);
+    client.batch_execute(&format!("DROP SCHEMA IF EXISTS {0} CASCADE; CREATE SCHEMA {0}", schema)).await.unwrap();
+
+    let mut config: tokio_postgres::Config = dsn.parse().unwrap();
+    config.options(format!("-c search_path={}", schema));
+    Some(pool(config).unwrap())
+}
+
+#[cfg(test)]
+impl PostgresStore {
+    /// A migrated store on a schema of its own, see `scratch_pool`
+    pub async fn scratch(name: &str) -> Option<Self> {
+        let store = Self { pool: scratch_pool(name).await? };
+        store.migrate().await.unwrap();
+        Some(store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn migrates_an_empty_database() {
+        let store = match scratch_pool("migrates").await {
+            Some(pool) => PostgresStore { pool },
+            None => {
+                return;
+            }
+        };
+
+        assert_eq!(store.version().await.unwrap(), 0);
+        assert!(store.check().await.is_err());
+
+        store.migrate().await.unwrap();
+        assert_eq!(store.version().await.unwrap(), MIGRATIONS.last().unwrap().0);
+        store.check().await.unwrap();
+
+        // Applying the migrations again changes nothing
+        store.migrate().await.unwrap();
+        store.check().await.unwrap();
+    }
+}
@@ -0,0 +1,613 @@
+use async_trait::async_trait;
+use anyhow::anyhow;
+use rusqlite::{ params, Connection, OpenFlags, OptionalExtension, Row };
+use std::path::Path;
+use std::sync::{ Mutex, MutexGuard };
+
+use super::{
+    is_changed,
+    migrations,
+    video_id,
+    FeedCache,
+    Pause,
+    PauseScope,
+    PollStatus,
+    Upsert,
+    Video,
+    VideoStore,
+};
+use crate::data::Entry;
+use crate::delivery::{ Delivery, DeliveryKey, DeliveryStatus, Receipt };
+use crate::WebhookType;
+use crate::retry::RetryPolicy;
+
+/// A video as seen from one of its playlists
+const VIDEO_SELECT: &str =
+    "SELECT video.id AS id, playlist_video.playlist AS playlist, video.title AS title, video.author AS author, video.timestamp AS timestamp, playlist_video.hooked AS hooked, video.updated AS updated FROM video JOIN playlist_video ON playlist_video.video = video.id";
+
+impl Video {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            id: row.get("id")?,
+            playlist: row.get("playlist")?,
+            title: row.get("title")?,
+            author: row.get("author")?,
+            timestamp: row.get("timestamp")?,
+            hooked: row.get("hooked")?,
+            updated: row.get("updated")?,
+        })
+    }
+}
+
+impl Delivery {
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            video: row.get("video")?,
+            playlist: row.get("playlist")?,
+            destination: row.get("destination")?,
+            target: row.get("target")?,
+            status: row.get("status")?,
+            attempts: row.get("attempts")?,
+            last_error: row.get("last_error")?,
+            next_attempt_at: row.get("next_attempt_at")?,
+            updated_at: row.get("updated_at")?,
+            message_id: row.get("message_id")?,
+            thread_id: row.get("thread_id")?,
+            record_cid: row.get("record_cid")?,
+            edit_pending: row.get("edit_pending")?,
+        })
+    }
+}
+
+/// Stores videos, deliveries and playlists in a local SQLite database
+pub struct SqliteStore {
+    connection: Mutex<Connection>,
+}
+
+impl SqliteStore {
+    /// Opens the database and brings its schema up to date
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let mut connection = Connection::open(path)?;
+        migrations::migrate(&mut connection)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    /// Opens an existing database read-only, failing when its schema isn't up to date
+    pub fn open_existing(path: &Path) -> anyhow::Result<Self> {
+        if !path.exists() {
+            return Err(anyhow!("The database {} doesn't exist yet", path.display()));
+        }
+
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+        migrations::check(&connection)?;
+        Ok(Self { connection: Mutex::new(connection) })
+    }
+
+    fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("sqlite connection lock poisoned")
+    }
+}
+
+#[async_trait]
+impl VideoStore for SqliteStore {
+    async fn upsert_entry(&self, playlist: &str, entry: &Entry, hooked: bool) -> anyhow::Result<Upsert> {
+        let connection = self.connection();
+        let id = video_id(entry);
+        let existing: Option<(String, String)> = connection
+            .query_row(
+                "SELECT title, updated FROM video WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get("title")?, row.get("updated")?))
+            )
+            .optional()?;
+
+        let linked =
+            connection.execute(
+                "INSERT OR IGNORE INTO playlist_video (playlist, video, hooked) VALUES (?1, ?2, ?3)",
+                params![playlist, id, hooked as i32]
+            )? > 0;
+
+        let (title, updated) = match existing {
+            Some(existing) => existing,
+            None => {
+                connection.execute(
+                    "INSERT INTO video (id, title, author, timestamp, updated) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        id,
+                        entry.title,
+                        entry.author.as_ref().map_or("", |author| author.name.as_str()),
+                        entry.published,
+                        entry.updated
+                    ]
+                )?;
+                return Ok(Upsert::Inserted);
+            }
+        };
+        let unchanged = if linked { Upsert::Inserted } else { Upsert::Unchanged };
+
+        if title == entry.title && updated == entry.updated {
+            return Ok(unchanged);
+        }
+
+        connection.execute(
+            "UPDATE video SET title = ?2, updated = ?3 WHERE id = ?1",
+            params![id, entry.title, entry.updated]
+        )?;
+
+        if !is_changed(&title, &updated, entry) {
+            return Ok(unchanged);
+        }
+
+        connection.execute(
+            "UPDATE delivery SET edit_pending = 1 WHERE video = ?1 AND destination = ?2 AND status = ?3 AND message_id IS NOT NULL",
+            params![id, WebhookType::Discord.as_str(), DeliveryStatus::Delivered.as_str()]
+        )?;
+
+        Ok(Upsert::Changed)
+    }
+
+    async fn mark_hooked(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        self.connection().execute(
+            "UPDATE playlist_video SET hooked = 1 WHERE video = ?1 AND playlist = ?2",
+            params![video, playlist]
+        )?;
+        Ok(())
+    }
+
+    async fn unhooked_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<String>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            "SELECT video AS id FROM playlist_video WHERE hooked = 0 AND playlist = ?1"
+        )?;
+        let ids = stmt
+            .query_map(params![playlist], |row| row.get("id"))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(ids)
+    }
+
+    async fn pending_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            &format!(
+                "{} WHERE playlist_video.playlist = ?1 AND EXISTS (SELECT 1 FROM delivery WHERE delivery.video = video.id AND delivery.playlist = playlist_video.playlist AND delivery.status = ?2) ORDER BY video.timestamp ASC",
+                VIDEO_SELECT
+            )
+        )?;
+        let videos = stmt
+            .query_map(params![playlist, DeliveryStatus::Pending.as_str()], Video::from_row)?
+            .collect::<rusqlite::Result<Vec<Video>>>()?;
+
+        Ok(videos)
+    }
+
+    async fn is_known_playlist(&self, playlist: &str) -> anyhow::Result<bool> {
+        let known = self.connection().query_row(
+            "SELECT EXISTS (SELECT 1 FROM playlist WHERE id = ?1) OR EXISTS (SELECT 1 FROM playlist_video WHERE playlist = ?1)",
+            params![playlist],
+            |row| row.get(0)
+        )?;
+
+        Ok(known)
+    }
+
+    async fn mark_known_playlist(&self, playlist: &str) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO playlist (id) VALUES (?1)",
+            params![playlist]
+        )?;
+        Ok(())
+    }
+
+    async fn enqueue(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO delivery (video, playlist, destination, target, status) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                DeliveryStatus::Pending.as_str()
+            ]
+        )?;
+        Ok(())
+    }
+
+    async fn is_due(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let due: Option<bool> = self
+            .connection()
+            .query_row(
+                "SELECT status = ?5 AND (next_attempt_at IS NULL OR next_attempt_at <= CURRENT_TIMESTAMP) AS due FROM delivery WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+                params![
+                    key.video,
+                    key.playlist,
+                    key.destination.as_str(),
+                    key.target,
+                    DeliveryStatus::Pending.as_str()
+                ],
+                |row| row.get("due")
+            )
+            .optional()?;
+
+        Ok(due.unwrap_or(false))
+    }
+
+    async fn mark_delivered(&self, key: &DeliveryKey<'_>, receipt: &Receipt) -> anyhow::Result<()> {
+        self.connection().execute(
+            "UPDATE delivery SET status = ?5, attempts = attempts + 1, last_error = NULL, next_attempt_at = NULL, message_id = ?6, thread_id = ?7, record_cid = ?8, updated_at = CURRENT_TIMESTAMP, delivered_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                DeliveryStatus::Delivered.as_str(),
+                receipt.message_id,
+                receipt.thread_id,
+                receipt.record_cid
+            ]
+        )?;
+        Ok(())
+    }
+
+    async fn mark_failed(
+        &self,
+        key: &DeliveryKey<'_>,
+        error: &str,
+        policy: &RetryPolicy
+    ) -> anyhow::Result<DeliveryStatus> {
+        let attempts: u32 = self
+            .connection()
+            .query_row(
+                "SELECT attempts FROM delivery WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+                params![key.video, key.playlist, key.destination.as_str(), key.target],
+                |row| row.get("attempts")
+            )
+            .optional()?
+            .unwrap_or(0) + 1;
+
+        let status = if policy.is_exhausted(attempts) {
+            DeliveryStatus::Dead
+        } else {
+            DeliveryStatus::Pending
+        };
+
+        self.connection().execute(
+            "UPDATE delivery SET status = ?5, attempts = ?6, last_error = ?7, next_attempt_at = datetime('now', ?8), updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                status.as_str(),
+                attempts,
+                error,
+                format!("+{} seconds", policy.backoff(attempts).as_secs())
+            ]
+        )?;
+
+        Ok(status)
+    }
+
+    async fn skip_pending(&self, video: &str, playlist: &str) -> anyhow::Result<usize> {
+        let skipped = self.connection().execute(
+            "UPDATE delivery SET status = ?3, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND status IN (?4, ?5)",
+            params![
+                video,
+                playlist,
+                DeliveryStatus::Skipped.as_str(),
+                DeliveryStatus::Pending.as_str(),
+                DeliveryStatus::Dead.as_str()
+            ]
+        )?;
+
+        Ok(skipped)
+    }
+
+    async fn has_delivery(
+        &self,
+        video: &str,
+        destination: &WebhookType,
+        target: &str
+    ) -> anyhow::Result<bool> {
+        let exists = self.connection().query_row(
+            "SELECT EXISTS (SELECT 1 FROM delivery WHERE video = ?1 AND destination = ?2 AND target = ?3)",
+            params![video, destination.as_str(), target],
+            |row| row.get(0)
+        )?;
+
+        Ok(exists)
+    }
+
+    async fn pending_count(&self, video: &str, playlist: &str) -> anyhow::Result<i64> {
+        let count = self.connection().query_row(
+            "SELECT COUNT(*) FROM delivery WHERE video = ?1 AND playlist = ?2 AND status = ?3",
+            params![video, playlist, DeliveryStatus::Pending.as_str()],
+            |row| row.get(0)
+        )?;
+
+        Ok(count)
+    }
+
+    async fn pending_edits(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            "SELECT * FROM delivery WHERE playlist = ?1 AND edit_pending = 1"
+        )?;
+        let deliveries = stmt
+            .query_map(params![playlist], Delivery::from_row)?
+            .collect::<rusqlite::Result<Vec<Delivery>>>()?;
+
+        let mut edits = Vec::new();
+        for delivery in deliveries {
+            let video = connection
+                .query_row(
+                    &format!("{} WHERE video.id = ?1 AND playlist_video.playlist = ?2", VIDEO_SELECT),
+                    params![delivery.video, delivery.playlist],
+                    Video::from_row
+                )
+                .optional()?;
+            if let Some(video) = video {
+                edits.push((video, delivery));
+            }
+        }
+
+        Ok(edits)
+    }
+
+    async fn mark_edited(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.connection().execute(
+            "UPDATE delivery SET edit_pending = 0, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+            params![key.video, key.playlist, key.destination.as_str(), key.target]
+        )?;
+        Ok(())
+    }
+
+    async fn announced_for_playlist(&self, playlist: &str) -> anyhow::Result<Vec<Video>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            &format!(
+                "{} WHERE playlist_video.playlist = ?1 AND video.removed = 0 AND playlist_video.removed = 0 AND EXISTS (SELECT 1 FROM delivery WHERE delivery.video = video.id AND delivery.playlist = ?1 AND delivery.status = ?2)",
+                VIDEO_SELECT
+            )
+        )?;
+        let videos = stmt
+            .query_map(params![playlist, DeliveryStatus::Delivered.as_str()], Video::from_row)?
+            .collect::<rusqlite::Result<Vec<Video>>>()?;
+
+        Ok(videos)
+    }
+
+    async fn mark_removed(&self, video: &str) -> anyhow::Result<()> {
+        let connection = self.connection();
+        connection.execute("UPDATE video SET removed = 1 WHERE id = ?1", params![video])?;
+        connection.execute(
+            "UPDATE delivery SET status = ?2, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND status IN (?3, ?4)",
+            params![
+                video,
+                DeliveryStatus::Skipped.as_str(),
+                DeliveryStatus::Pending.as_str(),
+                DeliveryStatus::Dead.as_str()
+            ]
+        )?;
+        Ok(())
+    }
+
+    async fn mark_removed_from(&self, video: &str, playlist: &str) -> anyhow::Result<()> {
+        let connection = self.connection();
+        connection.execute(
+            "UPDATE playlist_video SET removed = 1 WHERE video = ?1 AND playlist = ?2",
+            params![video, playlist]
+        )?;
+        connection.execute(
+            "UPDATE delivery SET status = ?3, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND status IN (?4, ?5)",
+            params![
+                video,
+                playlist,
+                DeliveryStatus::Skipped.as_str(),
+                DeliveryStatus::Pending.as_str(),
+                DeliveryStatus::Dead.as_str()
+            ]
+        )?;
+        Ok(())
+    }
+
+    async fn pending_retractions(&self, playlist: &str) -> anyhow::Result<Vec<(Video, Delivery)>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            "SELECT delivery.* FROM delivery JOIN video ON video.id = delivery.video JOIN playlist_video ON playlist_video.video = delivery.video AND playlist_video.playlist = delivery.playlist WHERE delivery.playlist = ?1 AND delivery.status = ?2 AND (video.removed = 1 OR playlist_video.removed = 1)"
+        )?;
+        let deliveries = stmt
+            .query_map(params![playlist, DeliveryStatus::Delivered.as_str()], Delivery::from_row)?
+            .collect::<rusqlite::Result<Vec<Delivery>>>()?;
+
+        let mut retractions = Vec::new();
+        for delivery in deliveries {
+            let video = connection
+                .query_row(
+                    &format!("{} WHERE video.id = ?1 AND playlist_video.playlist = ?2", VIDEO_SELECT),
+                    params![delivery.video, delivery.playlist],
+                    Video::from_row
+                )
+                .optional()?;
+            if let Some(video) = video {
+                retractions.push((video, delivery));
+            }
+        }
+
+        Ok(retractions)
+    }
+
+    async fn mark_retracted(&self, key: &DeliveryKey<'_>) -> anyhow::Result<()> {
+        self.connection().execute(
+            "UPDATE delivery SET status = ?5, edit_pending = 0, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                DeliveryStatus::Retracted.as_str()
+            ]
+        )?;
+        Ok(())
+    }
+
+    async fn videos(&self, playlist: Option<&str>) -> anyhow::Result<Vec<Video>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            &format!(
+                "{} WHERE (?1 IS NULL OR playlist_video.playlist = ?1) ORDER BY video.timestamp DESC",
+                VIDEO_SELECT
+            )
+        )?;
+        let videos = stmt
+            .query_map(params![playlist], Video::from_row)?
+            .collect::<rusqlite::Result<Vec<Video>>>()?;
+
+        Ok(videos)
+    }
+
+    async fn deliveries(&self, video: &str) -> anyhow::Result<Vec<Delivery>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            "SELECT * FROM delivery WHERE video = ?1 ORDER BY playlist, destination, target"
+        )?;
+        let deliveries = stmt
+            .query_map(params![video], Delivery::from_row)?
+            .collect::<rusqlite::Result<Vec<Delivery>>>()?;
+
+        Ok(deliveries)
+    }
+
+    async fn replay(&self, video: &str, destination: Option<&str>) -> anyhow::Result<usize> {
+        let replayed = self.connection().execute(
+            "UPDATE delivery SET status = ?1, attempts = 0, last_error = NULL, next_attempt_at = NULL, edit_pending = 0, updated_at = CURRENT_TIMESTAMP WHERE video = ?2 AND (?3 IS NULL OR destination = ?3)",
+            params![DeliveryStatus::Pending.as_str(), video, destination]
+        )?;
+
+        Ok(replayed)
+    }
+
+    async fn feed_cache(&self, url: &str) -> anyhow::Result<Option<FeedCache>> {
+        let cache = self.connection()
+            .query_row(
+                "SELECT url, etag, last_modified, hash FROM feed_cache WHERE url = ?1",
+                params![url],
+                |row| {
+                    Ok(FeedCache {
+                        url: row.get("url")?,
+                        etag: row.get("etag")?,
+                        last_modified: row.get("last_modified")?,
+                        hash: row.get("hash")?,
+                    })
+                }
+            )
+            .optional()?;
+
+        Ok(cache)
+    }
+
+    async fn save_feed_cache(&self, cache: &FeedCache) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT INTO feed_cache (url, etag, last_modified, hash) VALUES (?1, ?2, ?3, ?4) ON CONFLICT (url) DO UPDATE SET etag = excluded.etag, last_modified = excluded.last_modified, hash = excluded.hash, fetched_at = CURRENT_TIMESTAMP",
+            params![cache.url, cache.etag, cache.last_modified, cache.hash]
+        )?;
+        Ok(())
+    }
+
+    async fn requeue_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let requeued = self.connection().execute(
+            "UPDATE delivery SET status = ?5, attempts = 0, last_error = NULL, next_attempt_at = NULL, edit_pending = 0, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                DeliveryStatus::Pending.as_str()
+            ]
+        )?;
+
+        Ok(requeued > 0)
+    }
+
+    async fn skip_delivery(&self, key: &DeliveryKey<'_>) -> anyhow::Result<bool> {
+        let skipped = self.connection().execute(
+            "UPDATE delivery SET status = ?5, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE video = ?1 AND playlist = ?2 AND destination = ?3 AND target = ?4 AND status IN (?6, ?7)",
+            params![
+                key.video,
+                key.playlist,
+                key.destination.as_str(),
+                key.target,
+                DeliveryStatus::Skipped.as_str(),
+                DeliveryStatus::Pending.as_str(),
+                DeliveryStatus::Dead.as_str()
+            ]
+        )?;
+
+        Ok(skipped > 0)
+    }
+
+    async fn pauses(&self) -> anyhow::Result<Vec<Pause>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare("SELECT scope, name FROM pause ORDER BY scope, name")?;
+        let pauses = stmt
+            .query_map([], |row| Ok(Pause { scope: row.get("scope")?, name: row.get("name")? }))?
+            .collect::<Result<Vec<Pause>, _>>()?;
+
+        Ok(pauses)
+    }
+
+    async fn set_paused(&self, scope: PauseScope, name: &str, paused: bool) -> anyhow::Result<()> {
+        let sql = if paused {
+            "INSERT OR IGNORE INTO pause (scope, name) VALUES (?1, ?2)"
+        } else {
+            "DELETE FROM pause WHERE scope = ?1 AND name = ?2"
+        };
+        self.connection().execute(sql, params![scope.as_str(), name])?;
+        Ok(())
+    }
+
+    async fn record_poll(&self, playlist: &str, status: &str) -> anyhow::Result<()> {
+        self.connection().execute(
+            "INSERT INTO poll (playlist, status) VALUES (?1, ?2) ON CONFLICT (playlist) DO UPDATE SET status = excluded.status, polled_at = CURRENT_TIMESTAMP",
+            params![playlist, status]
+        )?;
+        Ok(())
+    }
+
+    async fn polls(&self) -> anyhow::Result<Vec<PollStatus>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare("SELECT playlist, polled_at, status FROM poll ORDER BY playlist")?;
+        let polls = stmt
+            .query_map([], |row| {
+                Ok(PollStatus {
+                    playlist: row.get("playlist")?,
+                    polled_at: row.get("polled_at")?,
+                    status: row.get("status")?,
+                })
+            })?
+            .collect::<Result<Vec<PollStatus>, _>>()?;
+
+        Ok(polls)
+    }
+
+    async fn dead_letters(&self) -> anyhow::Result<Vec<Delivery>> {
+        let connection = self.connection();
+        let mut stmt = connection.prepare(
+            "SELECT * FROM delivery WHERE status = ?1 ORDER BY updated_at ASC"
+        )?;
+        let deliveries = stmt
+            .query_map(params![DeliveryStatus::Dead.as_str()], Delivery::from_row)?
+            .collect::<rusqlite::Result<Vec<Delivery>>>()?;
+
+        Ok(deliveries)
+    }
+
+    async fn requeue(&self, video: Option<&str>, destination: Option<&str>) -> anyhow::Result<usize> {
+        let requeued = self.connection().execute(
+            "UPDATE delivery SET status = ?1, attempts = 0, last_error = NULL, next_attempt_at = NULL, updated_at = CURRENT_TIMESTAMP WHERE status = ?2 AND (?3 IS NULL OR video = ?3) AND (?4 IS NULL OR destination = ?4)",
+            params![DeliveryStatus::Pending.as_str(), DeliveryStatus::Dead.as_str(), video, destination]
+        )?;
+
+        Ok(requeued)
+    }
+}
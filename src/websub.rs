@@ -0,0 +1,423 @@
+use axum::body::Bytes;
+use axum::extract::{ Path, Query, State };
+use axum::http::{ HeaderMap, StatusCode };
+use axum::routing::get;
+use axum::Router;
+use hmac::{ Hmac, Mac };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use sha1::Sha1;
+use std::collections::HashMap;
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::time::Instant;
+
+use crate::data::Entry;
+use crate::reload::Playlists;
+use crate::store::VideoStore;
+use crate::Playlist;
+
+/// How long to wait before asking the hub again when a subscription wasn't verified
+const RETRY_SUBSCRIBE: Duration = Duration::from_secs(600);
+
+/// Push notifications from YouTube's WebSub hub
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct WebSubConfig {
+    /// The address the callback server listens on
+    #[serde(default = "WebSubConfig::default_listen")]
+    pub listen: String,
+    /// The public URL the hub reaches the callback server at. Each playlist gets `/websub/<playlist id>` under it
+    pub callback_url: String,
+    /// Shared with the hub, which signs every push with it. Pushes without a valid signature are dropped
+    pub secret: String,
+    /// The lease to ask for, in seconds. The hub may grant less; leases are renewed at 80% of what was granted
+    #[serde(default = "WebSubConfig::default_lease_seconds")]
+    pub lease_seconds: u64,
+}
+
+impl std::fmt::Debug for WebSubConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("WebSubConfig")
+            .field("listen", &self.listen)
+            .field("callback_url", &self.callback_url)
+            .field("secret", &"***")
+            .field("lease_seconds", &self.lease_seconds)
+            .finish()
+    }
+}
+
+impl WebSubConfig {
+    fn default_listen() -> String {
+        "0.0.0.0:8080".to_string()
+    }
+
+    fn default_lease_seconds() -> u64 {
+        432000
+    }
+}
+
+/// The feed the hub is asked to push for a playlist: its channel's uploads when
+/// `channel_id` is set, the playlist itself otherwise
+pub fn topic(playlist: &Playlist) -> String {
+    match &playlist.channel_id {
+        Some(channel_id) =>
+            format!("https://www.youtube.com/xml/feeds/videos.xml?channel_id={}", channel_id),
+        None => format!("https://www.youtube.com/xml/feeds/videos.xml?playlist_id={}", playlist.id),
+    }
+}
+
+/// The Atom document the hub POSTs. Deleted videos come as `<at:deleted-entry>`
+#[derive(Debug, Default, Deserialize)]
+struct Push {
+    #[serde(default)]
+    entry: Vec<Entry>,
+    #[serde(rename = "deleted-entry", default)]
+    deleted: Vec<DeletedEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeletedEntry {
+    #[serde(rename = "@ref")]
+    reference: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Verification {
+    #[serde(rename = "hub.mode")]
+    mode: String,
+    #[serde(rename = "hub.topic")]
+    topic: String,
+    #[serde(rename = "hub.challenge")]
+    challenge: Option<String>,
+    #[serde(rename = "hub.lease_seconds")]
+    lease_seconds: Option<u64>,
+}
+
+struct WebSub {
+    config: WebSubConfig,
+    /// Where subscriptions are sent
+    hub: String,
+    playlists: Playlists,
+    /// When each playlist's subscription should next be renewed
+    renewals: Mutex<HashMap<String, Instant>>,
+    store: Arc<dyn VideoStore>,
+    pushed: UnboundedSender<String>,
+}
+
+/// Starts the callback server and keeps every playlist subscribed. The id of each playlist
+/// the hub pushed new entries for is sent on `pushed`
+pub async fn start(
+    config: WebSubConfig,
+    hub: String,
+    playlists: Playlists,
+    store: Arc<dyn VideoStore>,
+    client: reqwest::Client,
+    pushed: UnboundedSender<String>
+) -> anyhow::Result<()> {
+    let websub = Arc::new(WebSub {
+        playlists,
+        renewals: Mutex::new(HashMap::new()),
+        config,
+        hub,
+        store,
+        pushed,
+    });
+
+    let app = Router::new()
+        .route("/websub/:playlist", get(verify).post(push))
+        .with_state(websub.clone());
+    let listener = tokio::net::TcpListener::bind(&websub.config.listen).await?;
+    tracing::info!("Listening for WebSub callbacks on {}", &websub.config.listen);
+
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            tracing::error!("The WebSub callback server stopped: {:?}", e);
+        }
+    });
+    tokio::spawn(websub.renew(client));
+
+    Ok(())
+}
+
+impl WebSub {
+    /// The topic of a playlist the latest configuration has
+    fn topic(&self, playlist: &str) -> Option<String> {
+        self.playlists
+            .borrow()
+            .iter()
+            .find(|p| p.id == playlist)
+            .map(topic)
+    }
+
+    /// Asks the hub to (re)subscribe a playlist's topic. The hub confirms it asynchronously
+    /// by calling `verify`
+    async fn subscribe(&self, client: &reqwest::Client, playlist: &str, topic: &str) -> anyhow::Result<()> {
+        let callback = format!(
+            "{}/websub/{}",
+            self.config.callback_url.trim_end_matches('/'),
+            playlist
+        );
+        let form = [
+            ("hub.callback", callback),
+            ("hub.topic", topic.to_string()),
+            ("hub.mode", "subscribe".to_string()),
+            ("hub.verify", "async".to_string()),
+            ("hub.lease_seconds", self.config.lease_seconds.to_string()),
+            ("hub.secret", self.config.secret.clone()),
+        ];
+
+        let response = client.post(&self.hub).form(&form).send().await?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.text().await.unwrap_or_default();
+            return Err(anyhow::anyhow!("The hub responded with {}: {}", status, body));
+        }
+
+        Ok(())
+    }
+
+    /// Subscribes every topic, then renews each lease before it runs out. Playlists a reload
+    /// added are subscribed on the next round, and removed ones are left to lapse
+    async fn renew(self: Arc<Self>, client: reqwest::Client) {
+        loop {
+            let now = Instant::now();
+            let playlists = self.playlists.borrow().clone();
+            let due: Vec<String> = {
+                let mut renewals = self.renewals.lock().unwrap();
+                renewals.retain(|id, _| playlists.iter().any(|p| p.id == *id));
+                for playlist in playlists.iter() {
+                    renewals.entry(playlist.id.clone()).or_insert(now);
+                }
+                renewals
+                    .iter()
+                    .filter(|(_, at)| **at <= now)
+                    .map(|(playlist, _)| playlist.clone())
+                    .collect()
+            };
+
+            for playlist in due {
+                let topic = match self.topic(&playlist) {
+                    Some(topic) => topic,
+                    None => {
+                        continue;
+                    }
+                };
+
+                // Verification moves this further out once the hub confirms the lease
+                self.renewals.lock().unwrap().insert(playlist.clone(), now + RETRY_SUBSCRIBE);
+                match self.subscribe(&client, &playlist, &topic).await {
+                    Ok(()) => tracing::debug!("Asked the hub to subscribe {}", topic),
+                    Err(e) => tracing::error!("Unable to subscribe {}: {:?}", topic, e),
+                }
+            }
+
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        }
+    }
+}
+
+/// Answers the hub's verification of a subscribe or unsubscribe request, and records the granted lease
+async fn verify(
+    State(websub): State<Arc<WebSub>>,
+    Path(playlist): Path<String>,
+    Query(verification): Query<Verification>
+) -> (StatusCode, String) {
+    if websub.topic(&playlist).as_ref() != Some(&verification.topic) {
+        return (StatusCode::NOT_FOUND, String::new());
+    }
+
+    match verification.mode.as_str() {
+        "subscribe" => {
+            let lease = verification.lease_seconds.unwrap_or(websub.config.lease_seconds);
+            let renew_after = Duration::from_secs_f64((lease as f64) * 0.8);
+            websub.renewals.lock().unwrap().insert(playlist, Instant::now() + renew_after);
+            tracing::info!("Subscribed to {} for {}s", &verification.topic, lease);
+        }
+        "unsubscribe" => {}
+        "denied" => {
+            tracing::warn!("The hub denied the subscription to {}", &verification.topic);
+            return (StatusCode::OK, String::new());
+        }
+        _ => {
+            return (StatusCode::NOT_FOUND, String::new());
+        }
+    }
+
+    (StatusCode::OK, verification.challenge.unwrap_or_default())
+}
+
+/// Stores the entries the hub pushed for a playlist, and wakes the daemon up to deliver them.
+/// A channel's uploads aren't necessarily in the playlist, so for a playlist subscribed through
+/// its `channel_id` a push only wakes the daemon up to poll the playlist's feed
+async fn push(
+    State(websub): State<Arc<WebSub>>,
+    Path(playlist): Path<String>,
+    headers: HeaderMap,
+    body: Bytes
+) -> StatusCode {
+    let is_channel = match websub.playlists.borrow().iter().find(|p| p.id == playlist) {
+        Some(p) => p.channel_id.is_some(),
+        None => {
+            return StatusCode::NOT_FOUND;
+        }
+    };
+
+    // The callback URL is public, so anything unsigned could have come from anyone
+    let signature = headers.get("x-hub-signature").and_then(|value| value.to_str().ok());
+    if !is_signed(&websub.config.secret, &body, signature) {
+        // The hub must still get a 2xx, or it keeps retrying content we'll never accept
+        tracing::warn!("Ignoring a push for {} with a bad signature", &playlist);
+        return StatusCode::ACCEPTED;
+    }
+
+    let push = match
+        std::str
+            ::from_utf8(&body)
+            .map_err(|e| e.to_string())
+            .and_then(|body| quick_xml::de::from_str::<Push>(body).map_err(|e| e.to_string()))
+    {
+        Ok(push) => push,
+        Err(e) => {
+            tracing::error!("Unable to parse a push for {}: {}", &playlist, e);
+            return StatusCode::BAD_REQUEST;
+        }
+    };
+
+    // Entries stored before a playlist's first poll would make it look known, and that poll would
+    // announce its backlog instead of seeding it. The poll this push asks for stores them instead
+    let known = match websub.store.is_known_playlist(&playlist).await {
+        Ok(known) => known,
+        Err(e) => {
+            tracing::error!("Unable to tell whether {} was polled before: {:?}", &playlist, e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    };
+    if !is_channel && known {
+        for entry in &push.entry {
+            if let Err(e) = websub.store.upsert_entry(&playlist, entry, false).await {
+                tracing::error!("Unable to store {}: {:?}", &entry.id, e);
+                return StatusCode::INTERNAL_SERVER_ERROR;
+            }
+        }
+    }
+
+    // A deleted video is gone from YouTube, whichever feed reported it
+    for deleted in &push.deleted {
+        let id = deleted.reference.replace("yt:video:", "");
+        if let Err(e) = websub.store.mark_removed(&id).await {
+            tracing::error!("Unable to mark {} removed: {:?}", &id, e);
+            return StatusCode::INTERNAL_SERVER_ERROR;
+        }
+    }
+
+    tracing::info!(
+        "The hub pushed {} entries and {} deletions for {}",
+        push.entry.len(),
+        push.deleted.len(),
+        &playlist
+    );
+    let _ = websub.pushed.send(playlist);
+
+    StatusCode::OK
+}
+
+/// `X-Hub-Signature` is `sha1=` followed by the hex HMAC-SHA1 of the body, keyed with the secret
+fn is_signed(secret: &str, body: &[u8], signature: Option<&str>) -> bool {
+    let digest = match
+        signature
+            .and_then(|signature| signature.strip_prefix("sha1="))
+            .and_then(|digest| hex::decode(digest).ok())
+    {
+        Some(digest) => digest,
+        None => {
+            return false;
+        }
+    };
+
+    let mut mac = match Hmac::<Sha1>::new_from_slice(secret.as_bytes()) {
+        Ok(mac) => mac,
+        Err(_) => {
+            return false;
+        }
+    };
+    mac.update(body);
+    mac.verify_slice(&digest).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::MemoryStore;
+    use tokio::sync::{ mpsc, watch };
+
+    const PUSH: &[u8] =
+        b"<feed xmlns:yt=\"http://www.youtube.com/xml/schemas/2015\" xmlns=\"http://www.w3.org/2005/Atom\">
+        <entry>
+            <id>yt:video:a</id>
+            <title>First</title>
+            <published>2024-01-01T00:00:00+00:00</published>
+            <updated>2024-01-01T00:00:00+00:00</updated>
+        </entry>
+    </feed>";
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha1>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        format!("sha1={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    #[test]
+    fn accepts_a_push_signed_with_the_secret() {
+        let signature = sign("secret", b"<feed/>");
+        assert!(is_signed("secret", b"<feed/>", Some(&signature)));
+    }
+
+    #[test]
+    fn rejects_anything_else() {
+        let signature = sign("secret", b"<feed/>");
+        assert!(!is_signed("secret", b"<feed></feed>", Some(&signature)));
+        assert!(!is_signed("other", b"<feed/>", Some(&signature)));
+        assert!(!is_signed("secret", b"<feed/>", Some(signature.trim_start_matches("sha1="))));
+        assert!(!is_signed("secret", b"<feed/>", Some("sha1=not-hex")));
+        assert!(!is_signed("secret", b"<feed/>", None));
+    }
+
+    #[tokio::test]
+    async fn leaves_entries_of_a_playlist_never_polled_to_its_first_poll() {
+        let playlist: Playlist = serde_yaml::from_str(
+            "id: PL1
+name: uploads
+webhooks:
+  - destination: discord
+    urls: [https://discord.com/api/webhooks/1/token]"
+        ).unwrap();
+        let (_, playlists) = watch::channel(Arc::new(vec![playlist]));
+        let (pushed, mut polls) = mpsc::unbounded_channel();
+        let store = Arc::new(MemoryStore::default());
+        let websub = Arc::new(WebSub {
+            config: serde_yaml::from_str("callback_url: https://relay.test\nsecret: secret").unwrap(),
+            hub: "https://hub.test".to_string(),
+            playlists,
+            renewals: Mutex::new(HashMap::new()),
+            store: store.clone(),
+            pushed,
+        });
+        let mut headers = HeaderMap::new();
+        headers.insert("x-hub-signature", sign("secret", PUSH).parse().unwrap());
+
+        let status = push(State(websub.clone()), Path("PL1".to_string()), headers.clone(), Bytes::from(PUSH)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(polls.recv().await.unwrap(), "PL1");
+        assert!(!store.is_known_playlist("PL1").await.unwrap());
+        assert!(store.videos(None).await.unwrap().is_empty());
+
+        // Once the playlist was polled, pushed entries are stored right away
+        store.mark_known_playlist("PL1").await.unwrap();
+        let status = push(State(websub), Path("PL1".to_string()), headers, Bytes::from(PUSH)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(polls.recv().await.unwrap(), "PL1");
+        assert_eq!(store.unhooked_for_playlist("PL1").await.unwrap(), vec!["a".to_string()]);
+    }
+}
@@ -0,0 +1,430 @@
+use anyhow::anyhow;
+use futures::future;
+use reqwest::header::{ ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED };
+use reqwest::{ RequestBuilder, Response, StatusCode, Url };
+use schemars::JsonSchema;
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+use std::time::{ Duration, Instant };
+use tokio::sync::Semaphore;
+
+use crate::data::Feed;
+use crate::metrics::metrics;
+use crate::services::ServicesConfig;
+use crate::store::{ FeedCache, VideoStore };
+use crate::Playlist;
+
+/// How many entries a playlist feed lists at most
+pub const FEED_SIZE: usize = 15;
+
+/// Where the watch page of a video says whether it can be played
+const PLAYABILITY_STATUS: &str = "\"playabilityStatus\":";
+
+/// How many videos the YouTube Data API lists per page, at most
+const PAGE_SIZE: &str = "50";
+
+/// How feeds and other YouTube lookups are fetched
+#[derive(Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct FetchConfig {
+    /// How many feeds are fetched at the same time
+    #[serde(default = "FetchConfig::default_concurrency")]
+    pub concurrency: usize,
+    /// Seconds before a single request is given up on
+    #[serde(default = "FetchConfig::default_timeout")]
+    pub timeout: u64,
+    /// The most requests started per second against any one host
+    #[serde(default = "FetchConfig::default_requests_per_second")]
+    pub requests_per_second: f64,
+    /// A YouTube Data API key, used to list every video of playlists longer than their feed
+    pub api_key: Option<String>,
+}
+
+impl std::fmt::Debug for FetchConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FetchConfig")
+            .field("concurrency", &self.concurrency)
+            .field("timeout", &self.timeout)
+            .field("requests_per_second", &self.requests_per_second)
+            .field("api_key", &self.api_key.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            concurrency: Self::default_concurrency(),
+            timeout: Self::default_timeout(),
+            requests_per_second: Self::default_requests_per_second(),
+            api_key: None,
+        }
+    }
+}
+
+impl FetchConfig {
+    fn default_concurrency() -> usize {
+        4
+    }
+
+    fn default_timeout() -> u64 {
+        30
+    }
+
+    fn default_requests_per_second() -> f64 {
+        2.0
+    }
+}
+
+/// A page of `playlistItems`
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItems {
+    next_page_token: Option<String>,
+    #[serde(default)]
+    items: Vec<PlaylistItem>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlaylistItem {
+    content_details: ContentDetails,
+    status: Option<ItemStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ContentDetails {
+    video_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ItemStatus {
+    privacy_status: String,
+}
+
+impl PlaylistItem {
+    /// Private and deleted videos stay in a playlist, but nobody else can watch them there
+    fn is_watchable(&self) -> bool {
+        self.status
+            .as_ref()
+            .is_none_or(|status| status.privacy_status == "public" || status.privacy_status == "unlisted")
+    }
+}
+
+/// A feed that was fetched
+pub enum Fetched {
+    /// The feed changed since it was last fetched. `cache` is saved once its entries are stored
+    Changed(Feed, FeedCache),
+    /// YouTube answered 304, or sent the same document as last time
+    Unchanged,
+}
+
+/// Talks to YouTube, fetching up to `concurrency` feeds at once and spacing out
+/// the requests to each host
+pub struct YouTube {
+    client: reqwest::Client,
+    config: FetchConfig,
+    services: ServicesConfig,
+    permits: Semaphore,
+    /// When the next request to each host may start
+    hosts: Mutex<HashMap<String, Instant>>,
+}
+
+impl YouTube {
+    pub fn new(client: reqwest::Client, config: FetchConfig, services: ServicesConfig) -> Self {
+        Self {
+            permits: Semaphore::new(config.concurrency.max(1)),
+            client,
+            config,
+            services,
+            hosts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Fetches the feed of every playlist concurrently. The feeds come back in the order of `playlists`
+    pub async fn fetch_feeds(
+        &self,
+        playlists: &[Playlist],
+        cache: Option<&dyn VideoStore>
+    ) -> Vec<Option<Fetched>> {
+        future::join_all(playlists.iter().map(|playlist| self.fetch_feed(playlist, cache))).await
+    }
+
+    /// Fetches and parses the Atom feed of a playlist. With a `cache`, the feed is fetched
+    /// conditionally and isn't parsed again when it hasn't changed since it was last stored
+    pub async fn fetch_feed(&self, playlist: &Playlist, cache: Option<&dyn VideoStore>) -> Option<Fetched> {
+        let _permit = self.permits.acquire().await.ok()?;
+        let url = self.services.feed(&playlist.id);
+
+        let cached = match cache {
+            Some(store) =>
+                match store.feed_cache(&url).await {
+                    Ok(cached) => cached,
+                    Err(e) => {
+                        tracing::warn!("Unable to read the cached feed of {}: {:?}", &playlist.name, e);
+                        None
+                    }
+                }
+            None => None,
+        };
+
+        let mut request = self.request(&url).await;
+        if let Some(cached) = &cached {
+            if let Some(etag) = &cached.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &cached.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let started = Instant::now();
+        let response = request.send().await;
+
+        let response = match response {
+            Ok(response) => {
+                metrics().feed_fetched(&playlist.id, response.status().as_str(), started.elapsed());
+                response
+            }
+            Err(e) => {
+                let status = if e.is_timeout() { "timeout" } else { "error" };
+                metrics().feed_fetched(&playlist.id, status, started.elapsed());
+                tracing::error!("Unable to fetch the feed for playlist {}: {}", &playlist.name, e);
+                return None;
+            }
+        };
+
+        if response.status() == StatusCode::NOT_MODIFIED && cached.is_some() {
+            metrics().feed_cache_hit(&playlist.id, "not_modified");
+            tracing::debug!("The feed of {} wasn't modified", &playlist.name);
+            return Some(Fetched::Unchanged);
+        }
+
+        let etag = header(&response, ETAG);
+        let last_modified = header(&response, LAST_MODIFIED);
+        match response.text().await {
+            Ok(body) => {
+                let hash = hex::encode(Sha256::digest(body.as_bytes()));
+                if cached.is_some_and(|cached| cached.hash == hash) {
+                    metrics().feed_cache_hit(&playlist.id, "identical");
+                    tracing::debug!("The feed of {} is the same as last time", &playlist.name);
+                    return Some(Fetched::Unchanged);
+                }
+
+                match quick_xml::de::from_str::<Feed>(&body) {
+                    Ok(data) => {
+                        let cache = FeedCache { url, etag, last_modified, hash };
+                        Some(Fetched::Changed(data, cache))
+                    }
+                    Err(e) => {
+                        tracing::error!("{}", e.to_string());
+                        None
+                    }
+                }
+            }
+            Err(e) => {
+                tracing::error!("{}", e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Every video of a playlist anyone can watch, listed by the YouTube Data API.
+    /// `None` when there is no `api_key` to ask it with
+    pub async fn playlist_items(&self, playlist: &str) -> anyhow::Result<Option<HashSet<String>>> {
+        let key = match &self.config.api_key {
+            Some(key) => key,
+            None => {
+                return Ok(None);
+            }
+        };
+        let url = format!("{}/playlistItems", self.services.youtube_api.trim_end_matches('/'));
+
+        let mut videos = HashSet::new();
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut query = vec![
+                ("part", "contentDetails,status"),
+                ("playlistId", playlist),
+                ("maxResults", PAGE_SIZE),
+                ("key", key.as_str()),
+            ];
+            if let Some(page_token) = &page_token {
+                query.push(("pageToken", page_token.as_str()));
+            }
+
+            // The key is part of the URL, so errors leave it out
+            let response = self
+                .request(&url).await
+                .query(&query)
+                .send().await
+                .map_err(|e| e.without_url())?;
+            if !response.status().is_success() {
+                return Err(anyhow!("The YouTube Data API responded with {} for {}", response.status(), playlist));
+            }
+            let page: PlaylistItems = response.json().await.map_err(|e| e.without_url())?;
+
+            videos.extend(
+                page.items
+                    .iter()
+                    .filter(|item| item.is_watchable())
+                    .map(|item| item.content_details.video_id.clone())
+            );
+            match page.next_page_token {
+                Some(next) => {
+                    page_token = Some(next);
+                }
+                None => {
+                    return Ok(Some(videos));
+                }
+            }
+        }
+    }
+
+    /// Asks oEmbed whether a video can still be watched. Deleted videos answer 404 and private ones 403;
+    /// public videos whose owner disabled embedding answer 401, and stay available. A video oEmbed
+    /// calls gone is confirmed against the `playabilityStatus` of its watch page. Anything unexpected,
+    /// a watch page without that status included, is an error, so a flaky lookup never causes a retraction
+    pub async fn is_available(&self, id: &str) -> anyhow::Result<bool> {
+        let response = self
+            .request(&self.services.oembed).await
+            .query(
+                &[
+                    ("url", self.services.watch(id)),
+                    ("format", "json".to_string()),
+                ]
+            )
+            .send().await?;
+
+        match response.status() {
+            StatusCode::OK | StatusCode::UNAUTHORIZED => {
+                return Ok(true);
+            }
+            StatusCode::FORBIDDEN | StatusCode::NOT_FOUND => {}
+            status => {
+                return Err(anyhow!("oEmbed responded with {} for {}", status, id));
+            }
+        }
+
+        let page = self
+            .request(&self.services.watch(id)).await
+            .send().await?
+            .error_for_status()?
+            .text().await?;
+        is_playable(&page)
+    }
+
+    /// Waits for the host's next free slot, then builds a GET with the configured timeout
+    async fn request(&self, url: &str) -> RequestBuilder {
+        let host = Url::parse(url)
+            .ok()
+            .and_then(|url| url.host_str().map(|host| host.to_string()))
+            .unwrap_or_default();
+        let spacing = Duration::from_secs_f64(1.0 / self.config.requests_per_second.max(0.001));
+
+        let slot = {
+            let mut hosts = self.hosts.lock().unwrap();
+            let now = Instant::now();
+            let slot = hosts.get(&host).map_or(now, |next| (*next).max(now));
+            hosts.insert(host, slot + spacing);
+            slot
+        };
+        tokio::time::sleep_until(slot.into()).await;
+
+        self.client.get(url).timeout(Duration::from_secs(self.config.timeout))
+    }
+}
+
+fn header(response: &Response, name: reqwest::header::HeaderName) -> Option<String> {
+    response.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string())
+}
+
+/// The parts of a watch page's `playabilityStatus` that tell a gone video apart
+#[derive(Debug, Deserialize)]
+struct PlayabilityStatus {
+    status: String,
+    reason: Option<String>,
+    #[serde(default)]
+    messages: Vec<String>,
+}
+
+/// Whether the `playabilityStatus` a watch page embeds lets the video be played. Only the statuses
+/// of a deleted or private video count as gone; a page without one, such as a consent or bot check
+/// interstitial, is an error
+fn is_playable(page: &str) -> anyhow::Result<bool> {
+    let start = match page.find(PLAYABILITY_STATUS) {
+        Some(start) => start + PLAYABILITY_STATUS.len(),
+        None => {
+            return Err(anyhow!("The watch page has no playabilityStatus"));
+        }
+    };
+    let status: PlayabilityStatus = match serde_json::Deserializer::from_str(&page[start..]).into_iter().next() {
+        Some(Ok(status)) => status,
+        _ => {
+            return Err(anyhow!("Unable to read the playabilityStatus of the watch page"));
+        }
+    };
+
+    let private = status.reason
+        .iter()
+        .chain(status.messages.iter())
+        .any(|message| message.to_lowercase().contains("private"));
+    match status.status.as_str() {
+        "ERROR" | "UNPLAYABLE" => Ok(false),
+        "LOGIN_REQUIRED" if private => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(status: &str) -> String {
+        format!(
+            "<script nonce=\"x\">var ytInitialPlayerResponse = {{\"responseContext\":{{}},\"playabilityStatus\":{},\"videoDetails\":{{\"videoId\":\"jNQXAC9IVRw\"}}}};</script>",
+            status
+        )
+    }
+
+    #[test]
+    fn playable_videos() {
+        let ok = page(r#"{"status":"OK","playableInEmbed":true,"miniplayer":{"miniplayerRenderer":{}}}"#);
+        assert!(is_playable(&ok).unwrap());
+
+        let age_restricted = page(
+            r#"{"status":"LOGIN_REQUIRED","reason":"Sign in to confirm your age","messages":["This video may be inappropriate for some users."]}"#
+        );
+        assert!(is_playable(&age_restricted).unwrap());
+    }
+
+    #[test]
+    fn gone_videos() {
+        let deleted = page(r#"{"status":"ERROR","reason":"This video isn't available anymore","errorScreen":{}}"#);
+        assert!(!is_playable(&deleted).unwrap());
+
+        let unplayable = page(r#"{"status":"UNPLAYABLE","reason":"Video unavailable","errorScreen":{}}"#);
+        assert!(!is_playable(&unplayable).unwrap());
+
+        let private = page(
+            r#"{"status":"LOGIN_REQUIRED","messages":["This is a private video. Please sign in to verify that you may have access."],"reason":"Video unavailable"}"#
+        );
+        assert!(!is_playable(&private).unwrap());
+    }
+
+    #[test]
+    fn pages_without_a_status_are_errors() {
+        let consent = "<html><body><form action=\"https://consent.youtube.com/save\" method=\"POST\">Before you continue to YouTube</form></body></html>";
+        assert!(is_playable(consent).is_err());
+
+        let truncated = "var ytInitialPlayerResponse = {\"playabilityStatus\":{\"status\":";
+        assert!(is_playable(truncated).is_err());
+    }
+}